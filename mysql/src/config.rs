@@ -24,3 +24,17 @@ pub const GOOBIE_MYSQL_TABLE_NAME: &str =
 /// Keep connection alive, previously was doing wait_timeout but there
 /// are some other reasons why sometimes it wouldn't work
 pub const PING_INTERVAL: u64 = 30;
+
+/// How long `*Sync` methods block for when the query doesn't set `timeout`, in seconds
+pub const SYNC_QUERY_TIMEOUT: u64 = 60;
+
+/// How many times a lane with waiting queries can be passed over for higher ones before it gets a
+/// turn anyway
+pub const LANE_STARVATION_LIMIT: u32 = 8;
+
+/// Where the slow query log goes, relative to the server's folder
+pub const SLOW_QUERY_LOG_DIR: &str = "garrysmod/data/goobie_mysql";
+/// Size a slow query log file can grow to before it's rotated
+pub const SLOW_QUERY_LOG_MAX_BYTES: u64 = 5 * 1024 * 1024;
+/// How many rotated slow query logs are kept besides the current one, at least 1
+pub const SLOW_QUERY_LOG_BACKUPS: u32 = 3;
@@ -1,24 +1,34 @@
-use anyhow::anyhow;
 use gmodx::lua::{LuaResultExt, Nil};
 use sqlx::Connection;
 use sqlx::mysql::MySqlConnection;
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::sync::{Arc, atomic::Ordering};
 
-use crate::state::State;
+use crate::{
+    error::{Closed, NotConnected},
+    state::State,
+};
 
 use super::{
     reconnect,
     types::{ConnMessage, ConnMeta},
 };
 
-pub async fn handle_messages(
-    mut receiver: mpsc::UnboundedReceiver<ConnMessage>,
-    meta: Arc<ConnMeta>,
-) {
+pub async fn handle_messages(meta: Arc<ConnMeta>) {
     let mut db_conn: Option<MySqlConnection> = None;
 
-    while let Some(msg) = receiver.recv().await {
+    loop {
+        let Some(msg) = meta.pop() else {
+            // a push before we got here leaves a permit, so this can't miss it
+            meta.queued.notified().await;
+            continue;
+        };
+
+        // everything that was queued before Close gets failed, not run
+        if meta.closed.load(Ordering::Acquire) && !matches!(msg, ConnMessage::Close(_)) {
+            reject(&meta, msg);
+            continue;
+        }
+
         match msg {
             ConnMessage::Connect(callback) => {
                 reconnect::connect(&mut db_conn, &meta, callback).await;
@@ -27,19 +37,44 @@ pub async fn handle_messages(
                 disconnect(&mut db_conn, &meta, callback).await;
             }
             ConnMessage::Query(query) => {
-                reconnect::query(&mut db_conn, &meta, query).await;
+                reconnect::query(&mut db_conn, &meta, *query).await;
             }
             ConnMessage::Ping(callback) => {
                 ping(&mut db_conn, callback).await;
             }
-            // This should be called after "disconnect"
-            ConnMessage::Close => {
+            ConnMessage::Close(callback) => {
+                disconnect(&mut db_conn, &meta, callback).await;
+                let left: Vec<_> = meta.queue.lock().unwrap().drain().collect();
+                left.into_iter().for_each(|msg| reject(&meta, msg));
+                meta.dequeued.notify_all();
                 break;
             }
         }
     }
 }
 
+/// Gives a "connection is closed" error to the message's callback, and to `on_error` for queries
+fn reject(meta: &ConnMeta, msg: ConnMessage) {
+    let callback = match msg {
+        ConnMessage::Query(query) => {
+            reconnect::fail_query(meta, *query, Closed.into());
+            return;
+        }
+        ConnMessage::Connect(callback)
+        | ConnMessage::Disconnect(callback)
+        | ConnMessage::Ping(callback)
+        | ConnMessage::Close(callback) => callback,
+    };
+
+    if let Some(callback) = callback {
+        gmodx::next_tick(move |state| {
+            callback
+                .call::<()>(state, crate::error::to_error_table(state, &Closed.into()))
+                .log();
+        });
+    }
+}
+
 async fn disconnect(
     db_conn: &mut Option<MySqlConnection>,
     meta: &ConnMeta,
@@ -75,7 +110,7 @@ async fn ping(db_conn: &mut Option<MySqlConnection>, callback: Option<gmodx::lua
                     callback
                         .call::<()>(
                             state,
-                            crate::error::to_error_table(state, &anyhow!("connection is not open")),
+                            crate::error::to_error_table(state, &NotConnected.into()),
                         )
                         .log();
                 });
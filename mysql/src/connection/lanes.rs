@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+
+use anyhow::{Result, bail};
+
+use crate::{LANE_STARVATION_LIMIT, query::Priority};
+
+use super::types::ConnMessage;
+
+/// What to do with a query that's sent while `max_queue` queries are already waiting
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Fail the new query
+    #[default]
+    Reject,
+    /// Fail the oldest waiting low priority query to make room, or the new one if there's none
+    DropLow,
+    /// `*Sync` calls wait for room, for up to their timeout, everything else is rejected
+    Block,
+}
+
+impl OverflowPolicy {
+    pub fn parse(policy: &str) -> Result<Self> {
+        Ok(match policy {
+            "reject" => Self::Reject,
+            "drop_low" => Self::DropLow,
+            "block" => Self::Block,
+            _ => bail!(
+                "queue_overflow must be \"reject\", \"drop_low\" or \"block\", got \"{policy}\""
+            ),
+        })
+    }
+}
+
+/// Messages waiting for the handler, queries wait in the lane of their priority and everything
+/// else in the normal one, so it stays in order with most queries
+///
+/// Messages that change the session (`Start`, `Disconnect` and the statements of a transaction)
+/// keep their place, everything queued before them runs first and nothing queued after them can
+/// overtake them, priorities only reorder what's between them
+#[derive(Default)]
+pub struct Lanes {
+    groups: VecDeque<Group>,
+    /// How many times each lane was passed over while it had something waiting
+    skipped: [u32; 3],
+    queries: usize,
+}
+
+enum Group {
+    InOrder(ConnMessage),
+    Lanes([VecDeque<ConnMessage>; 3]),
+}
+
+fn keeps_its_place(msg: &ConnMessage) -> bool {
+    match msg {
+        ConnMessage::Connect(_) | ConnMessage::Disconnect(_) => true,
+        ConnMessage::Query(query) => query.in_order,
+        ConnMessage::Ping(_) | ConnMessage::Close(_) => false,
+    }
+}
+
+impl Lanes {
+    pub fn push(&mut self, msg: ConnMessage) {
+        if let ConnMessage::Query(_) = &msg {
+            self.queries += 1;
+        }
+        if keeps_its_place(&msg) {
+            self.groups.push_back(Group::InOrder(msg));
+            return;
+        }
+
+        let lane = match &msg {
+            ConnMessage::Query(query) => query.priority,
+            _ => Priority::Normal,
+        };
+        match self.groups.back_mut() {
+            Some(Group::Lanes(lanes)) => lanes[lane as usize].push_back(msg),
+            _ => {
+                let mut lanes: [VecDeque<ConnMessage>; 3] = Default::default();
+                lanes[lane as usize].push_back(msg);
+                self.groups.push_back(Group::Lanes(lanes));
+            }
+        }
+    }
+
+    /// Highest lane first, unless a lower one waited too long, then the lowest of those goes
+    pub fn pop(&mut self) -> Option<ConnMessage> {
+        let msg = match self.groups.pop_front()? {
+            Group::InOrder(msg) => msg,
+            Group::Lanes(mut lanes) => {
+                let msg = pop_lanes(&mut lanes, &mut self.skipped);
+                if !is_empty(&lanes) {
+                    self.groups.push_front(Group::Lanes(lanes));
+                }
+                msg
+            }
+        };
+        self.count_removed(&msg);
+        Some(msg)
+    }
+
+    /// Can come from any group, dropping a query doesn't change the session
+    pub fn pop_oldest_low(&mut self) -> Option<ConnMessage> {
+        let (idx, msg) =
+            self.groups
+                .iter_mut()
+                .enumerate()
+                .find_map(|(idx, group)| match group {
+                    Group::Lanes(lanes) => Some((idx, lanes[Priority::Low as usize].pop_front()?)),
+                    Group::InOrder(_) => None,
+                })?;
+        if matches!(&self.groups[idx], Group::Lanes(lanes) if is_empty(lanes)) {
+            self.groups.remove(idx);
+        }
+        self.count_removed(&msg);
+        Some(msg)
+    }
+
+    /// Queries waiting to be run
+    pub fn queries(&self) -> usize {
+        self.queries
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = ConnMessage> + '_ {
+        self.queries = 0;
+        self.groups.drain(..).flat_map(|group| match group {
+            Group::InOrder(msg) => vec![msg],
+            Group::Lanes(lanes) => lanes.into_iter().flatten().collect(),
+        })
+    }
+
+    fn count_removed(&mut self, msg: &ConnMessage) {
+        if let ConnMessage::Query(_) = msg {
+            self.queries -= 1;
+        }
+    }
+}
+
+fn is_empty(lanes: &[VecDeque<ConnMessage>; 3]) -> bool {
+    lanes.iter().all(VecDeque::is_empty)
+}
+
+/// Only called with at least one message waiting
+fn pop_lanes(lanes: &mut [VecDeque<ConnMessage>; 3], skipped: &mut [u32; 3]) -> ConnMessage {
+    let has_waiting = |lane: usize| !lanes[lane].is_empty();
+    let lane = (0..3)
+        .rev()
+        .find(|&lane| has_waiting(lane) && skipped[lane] >= LANE_STARVATION_LIMIT)
+        .or_else(|| (0..3).find(|&lane| has_waiting(lane)))
+        .expect("a group is removed once it's empty");
+
+    for (other, skipped) in skipped.iter_mut().enumerate() {
+        if other != lane && has_waiting(other) {
+            *skipped += 1;
+        }
+    }
+    skipped[lane] = 0;
+    lanes[lane].pop_front().expect("lane has a message waiting")
+}
@@ -1,11 +1,17 @@
 mod handler;
+mod lanes;
 mod options;
 mod reconnect;
+pub mod registry;
+mod session;
+mod slow_log;
+mod stats;
 mod types;
 mod userdata;
 
 use gmodx::lua::{self, Table, UserData};
-pub use types::Conn;
+pub use session::{Flavor, SessionInfo};
+pub use types::{Conn, PendingGuard};
 
 pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
     goobie_mysql.raw_set(state, "CONN_META", Conn::init_methods_table(state));
@@ -1,11 +1,23 @@
-use anyhow::anyhow;
-use gmodx::lua::{LuaResultExt, Nil};
-use sqlx::{Connection, mysql::MySqlConnection};
-use std::{sync::atomic::Ordering, time::Duration};
+use gmodx::lua::{self, LuaResultExt, Table};
+use sqlx::{Connection, Executor as _, mysql::MySqlConnection};
+use std::{
+    pin::pin,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
-use crate::{error::to_error_table, print_goobie_with_host, query::QueryResult, state::State};
+use crate::{
+    error::{
+        CallbackError, Cancelled, ConnectAttempt, ConnectCause, ConnectError, NotConnected,
+        to_error_table, to_query_error_table,
+    },
+    print_goobie_with_host,
+    query::{ColumnValue, QueryResult, QueryType, WarningsMode, warnings_table},
+    shutdown,
+    state::State,
+};
 
-use super::types::ConnMeta;
+use super::{SessionInfo, types::ConnMeta};
 
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 const MAX_ATTEMPTS: u32 = 3;
@@ -13,21 +25,32 @@ const RETRY_DELAY: Duration = Duration::from_secs(2);
 
 async fn connect_with_retry(
     opts: &sqlx::mysql::MySqlConnectOptions,
-) -> Result<MySqlConnection, sqlx::Error> {
-    let mut last_err = None;
-    for _ in 1..=MAX_ATTEMPTS {
-        match tokio::time::timeout(CONNECT_TIMEOUT, MySqlConnection::connect_with(opts)).await {
+) -> Result<MySqlConnection, ConnectError> {
+    let host = match opts.get_socket() {
+        Some(socket) => socket.display().to_string(),
+        None => format!("{}:{}", opts.get_host(), opts.get_port()),
+    };
+
+    let mut attempts = Vec::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let start = Instant::now();
+        let cause = match tokio::time::timeout(CONNECT_TIMEOUT, MySqlConnection::connect_with(opts))
+            .await
+        {
             Ok(Ok(conn)) => return Ok(conn),
-            Ok(Err(e)) => {
-                last_err = Some(e);
-            }
-            Err(_) => {
-                last_err = Some(sqlx::Error::PoolTimedOut);
-            }
+            Ok(Err(e)) => ConnectCause::Error(e),
+            Err(_) => ConnectCause::TimedOut,
+        };
+        attempts.push(ConnectAttempt {
+            host: host.clone(),
+            duration: start.elapsed(),
+            cause,
+        });
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(RETRY_DELAY).await;
         }
-        tokio::time::sleep(RETRY_DELAY).await;
     }
-    Err(last_err.unwrap())
+    Err(ConnectError(attempts))
 }
 
 pub async fn connect(
@@ -44,9 +67,21 @@ pub async fn connect(
     meta.state.set(State::Connecting);
 
     let res = match connect_with_retry(&meta.opts).await {
-        Ok(new_conn) => {
+        Ok(mut new_conn) => {
+            let session = match SessionInfo::load(&mut new_conn).await {
+                Ok(session) => session,
+                Err(e) => {
+                    print_goobie_with_host!(
+                        meta.opts.get_host(),
+                        "Failed to load session variables, using defaults: {e}"
+                    );
+                    SessionInfo::default()
+                }
+            };
+            *meta.session.write().unwrap() = session;
             *db_conn = Some(new_conn);
             meta.id.fetch_add(1, Ordering::Release);
+            meta.stats.record_connect();
             meta.state.set(State::Connected);
             Ok(())
         }
@@ -111,24 +146,50 @@ pub async fn query(
     meta: &ConnMeta,
     mut query: crate::query::Query,
 ) {
+    if query.cancel.is_cancelled() {
+        return fail_query(meta, query, Cancelled.into());
+    }
+
     let db_conn = match conn {
         Some(conn) => conn,
-        None => {
-            if let Some(callback) = query.callback {
-                gmodx::next_tick(move |state| {
-                    callback
-                        .call::<()>(
-                            state,
-                            to_error_table(state, &anyhow!("connection is not open")),
-                        )
-                        .log();
-                });
+        // callbacks, promises, coroutines and *Sync calls all get the error, and on_error too like
+        // any failure
+        None => return fail_query(meta, query, NotConnected.into()),
+    };
+
+    let session = meta.session.read().unwrap().clone();
+    // it could have reconnected to a different server since it was queued
+    query.flavor = session.flavor();
+    let cancel = query.cancel.clone();
+    let start = Instant::now();
+    let killed = {
+        let mut run = pin!(query.start(db_conn, &session));
+        tokio::select! {
+            // if it finished before it was cancelled, it keeps its result
+            _ = &mut run => false,
+            _ = cancel.cancelled() => {
+                // the kill has to be done before the next query starts, or it could hit that one
+                kill_query(meta, session.connection_id).await;
+                run.await;
+                true
             }
-            return;
         }
     };
-
-    query.start(db_conn).await;
+    // a killed query doesn't always fail, `SELECT SLEEP(n)` just returns 1, so what a read got is
+    // thrown away, a write that went through before the kill keeps its result or it'd be retried
+    // and done twice
+    let is_read = matches!(query.qtype, QueryType::FetchOne | QueryType::FetchAll);
+    if killed && (is_read || query.result.is_err()) {
+        query.result = Err(Cancelled.into());
+    }
+    let elapsed = start.elapsed();
+    meta.stats.record_query(&query, elapsed);
+    if let Some(slow_log) = &meta.slow_log {
+        slow_log.record(meta.opts.get_host(), &query, elapsed);
+    }
+    if let Err(e) = &query.result {
+        meta.stats.record_error(e, query.flavor);
+    }
 
     let should_reconnect = if let Err(e) = query.result.as_ref() {
         let should = should_reconnect(e);
@@ -152,82 +213,184 @@ pub async fn query(
         false
     };
 
-    handle_query_result(query);
+    handle_query_result(query, session.connection_id);
 
     if should_reconnect {
         attempt_reconnect(conn, meta).await;
     }
 }
 
-fn handle_query_result(query: crate::query::Query) {
-    gmodx::next_tick(move |state| match &query.result {
-        Ok(query_result) => {
-            let Some(callback) = query.callback else {
-                return;
-            };
-            use QueryResult::*;
-            match query_result {
-                Run => {
-                    callback.call::<()>(state, ()).log();
-                }
-                Execute(info) => {
-                    let info_table = state.create_table_with_capacity(0, 2);
-                    info_table.raw_set(state, "rows_affected", info.rows_affected());
-                    info_table.raw_set(state, "last_insert_id", info.last_insert_id());
-                    callback.call::<()>(state, (Nil, info_table)).log();
-                }
-                Rows(rows) => {
-                    let rows = match rows {
-                        Ok(rows) => rows,
-                        Err(err) => {
-                            callback.call::<()>(state, to_error_table(state, err)).log();
-                            return;
-                        }
-                    };
-
-                    let rows_table = state.create_table_with_capacity(rows.len() as i32, 0);
-                    for (idx, row) in rows.iter().enumerate() {
-                        let row_table = state.create_table_with_capacity(0, row.len() as i32);
-                        for value in row.iter() {
-                            row_table.raw_set(state, &value.column_name, &value.value);
-                        }
-                        rows_table.raw_set(state, idx as i32 + 1, &row_table);
-                    }
-                    callback.call::<()>(state, (Nil, rows_table)).log();
-                }
-                Row(row) => {
-                    let row = match row {
-                        Ok(Some(row)) => row,
-                        Ok(None) => {
-                            callback.call::<()>(state, ()).log();
-                            return;
-                        }
-                        Err(err) => {
-                            callback.call::<()>(state, to_error_table(state, err)).log();
-                            return;
-                        }
-                    };
-
-                    let row_table = state.create_table_with_capacity(0, row.len() as i32);
-                    for column_value in row.iter() {
-                        row_table.raw_set(state, &column_value.column_name, &column_value.value);
-                    }
-                    callback.call::<()>(state, (Nil, row_table)).log();
-                }
-            }
+/// Runs `KILL QUERY` from a separate connection, the one running the query is busy waiting on it
+async fn kill_query(meta: &ConnMeta, connection_id: Option<u64>) {
+    let Some(connection_id) = connection_id else {
+        print_goobie_with_host!(
+            meta.opts.get_host(),
+            "Can't cancel the running query, the server didn't tell us the connection id"
+        );
+        return;
+    };
+
+    let res = async {
+        let mut conn = MySqlConnection::connect_with(&meta.opts).await?;
+        conn.execute(format!("KILL QUERY {connection_id}").as_str())
+            .await?;
+        conn.close().await
+    }
+    .await;
+
+    if let Err(e) = res {
+        print_goobie_with_host!(
+            meta.opts.get_host(),
+            "Failed to cancel the running query: {e}"
+        );
+    }
+}
+
+/// Fails a query that never got to run
+pub fn fail_query(meta: &ConnMeta, mut query: crate::query::Query, err: anyhow::Error) {
+    meta.stats.record_error(&err, query.flavor);
+    query.result = Err(err);
+    handle_query_result(query, None);
+}
+
+fn handle_query_result(mut query: crate::query::Query, connection_id: Option<u64>) {
+    // a blocking *Sync call is waiting on the main thread, it builds the results itself
+    if let Some(sync) = query.sync.take() {
+        let _ = sync.send((query, connection_id));
+        return;
+    }
+
+    gmodx::next_tick(move |state| {
+        shutdown::record(query.result.is_ok());
+        if let Err(err) = call_query_callback(state, &query, connection_id) {
+            report_callback_error(state, &query, connection_id, err);
         }
-        Err(err) => {
-            if let Some(on_error) = query.on_error {
-                on_error
-                    .call::<()>(state, (to_error_table(state, err), query.trace))
-                    .log();
-            }
+    });
+}
+
+/// `err, res, warnings`, what callbacks get and what `*Sync` methods return
+pub type ResultArgs = (Option<Table>, Option<Table>, Option<Table>);
+
+/// Gives the error to `on_error` if the query failed, and returns what the caller gets
+pub fn sync_result(
+    state: &lua::State,
+    query: &crate::query::Query,
+    connection_id: Option<u64>,
+) -> ResultArgs {
+    report_query_error(state, query, connection_id);
+    result_args(state, query, connection_id)
+}
+
+/// Gives the result to the callback, or the coroutine that's awaiting it, and the error to
+/// `on_error` if the query failed, returns what the callback threw
+fn call_query_callback(
+    state: &lua::State,
+    query: &crate::query::Query,
+    connection_id: Option<u64>,
+) -> lua::Result<()> {
+    report_query_error(state, query, connection_id);
+    if let Some(promise) = &query.promise {
+        promise.settle(state, result_args(state, query, connection_id));
+    }
+    if let Some(coroutine) = &query.coroutine {
+        return coroutine.resume::<()>(state, result_args(state, query, connection_id));
+    }
+    match &query.callback {
+        Some(callback) => callback.call::<()>(state, result_args(state, query, connection_id)),
+        None => Ok(()),
+    }
+}
+
+fn report_query_error(state: &lua::State, query: &crate::query::Query, connection_id: Option<u64>) {
+    if let (Err(err), Some(on_error)) = (&query.result, &query.on_error) {
+        // asked for, not something that went wrong
+        if err.is::<Cancelled>() {
+            return;
+        }
+        let error_table = to_query_error_table(state, err, query, connection_id);
+        on_error
+            .call::<()>(state, (error_table, query.trace.clone()))
+            .log();
+    }
+}
+
+fn result_args(
+    state: &lua::State,
+    query: &crate::query::Query,
+    connection_id: Option<u64>,
+) -> ResultArgs {
+    let error_args = |err| {
+        (
+            Some(to_query_error_table(state, err, query, connection_id)),
+            None,
+            None,
+        )
+    };
 
-            if let Some(callback) = query.callback {
-                callback.call::<()>(state, to_error_table(state, err)).log();
+    let query_result = match &query.result {
+        Ok(query_result) => query_result,
+        Err(err) => return error_args(err),
+    };
+
+    // given as a third value, so callbacks that don't care about them don't change
+    let warnings = (query.settings.warnings == WarningsMode::Attach)
+        .then(|| warnings_table(state, &query.warnings));
+    use QueryResult::*;
+    match query_result {
+        Run | Row(Ok(None)) => (None, None, warnings),
+        Execute(info) => (None, Some(info.to_table(state)), warnings),
+        Rows(Ok(rows)) => (None, Some(rows_table(state, rows)), warnings),
+        Row(Ok(Some(row))) => {
+            let row_table = state.create_table_with_capacity(0, row.len() as i32);
+            for column_value in row.iter() {
+                row_table.raw_set(state, &column_value.column_name, &column_value.value);
             }
+            (None, Some(row_table), warnings)
         }
-    });
+        Returning(info, Ok(rows)) => {
+            let info_table = info.to_table(state);
+            info_table.raw_set(state, "rows", rows_table(state, rows));
+            (None, Some(info_table), warnings)
+        }
+        Rows(Err(err)) | Row(Err(err)) | Returning(_, Err(err)) => error_args(err),
+    }
+}
+
+/// Errors thrown by callbacks go to `on_error` with the query they came from, they're only printed
+/// if there's no `on_error` or `rethrow_callback_errors` is set
+fn report_callback_error(
+    state: &lua::State,
+    query: &crate::query::Query,
+    connection_id: Option<u64>,
+    err: lua::Error,
+) {
+    let Some(on_error) = &query.on_error else {
+        Err::<(), _>(err).log();
+        return;
+    };
+
+    let message = err.to_string();
+    if query.settings.rethrow_callback_errors {
+        Err::<(), _>(err).log();
+    }
+
+    let error_table =
+        to_query_error_table(state, &CallbackError(message).into(), query, connection_id);
+    on_error
+        .call::<()>(state, (error_table, query.trace.clone()))
+        .log();
+}
+
+fn rows_table(state: &lua::State, rows: &[Vec<ColumnValue>]) -> Table {
+    let rows_table = state.create_table_with_capacity(rows.len() as i32, 0);
+    for (idx, row) in rows.iter().enumerate() {
+        let row_table = state.create_table_with_capacity(0, row.len() as i32);
+        for value in row.iter() {
+            row_table.raw_set(state, &value.column_name, &value.value);
+        }
+        rows_table.raw_set(state, idx as i32 + 1, &row_table);
+    }
+    rows_table
 }
 
 async fn attempt_reconnect(conn: &mut Option<MySqlConnection>, meta: &ConnMeta) {
@@ -240,6 +403,7 @@ async fn attempt_reconnect(conn: &mut Option<MySqlConnection>, meta: &ConnMeta)
 
         if connect(conn, meta, None).await {
             print_goobie_with_host!(meta.opts.get_host(), "Reconnected!");
+            meta.stats.record_reconnect();
             reconnected = true;
             break;
         } else {
@@ -0,0 +1,29 @@
+use std::sync::{Arc, Mutex, Weak, atomic::Ordering};
+
+use super::types::ConnMeta;
+
+/// Every connection that's still alive, a connection is gone once its userdata was collected and
+/// its handler finished
+static CONNECTIONS: Mutex<Vec<Weak<ConnMeta>>> = Mutex::new(Vec::new());
+
+pub fn register(meta: &Arc<ConnMeta>) {
+    let mut conns = CONNECTIONS.lock().unwrap();
+    conns.retain(|conn| conn.strong_count() > 0);
+    conns.push(Arc::downgrade(meta));
+}
+
+/// The open connection made with that `name` option
+pub fn find(name: &str) -> Option<Arc<ConnMeta>> {
+    all()
+        .into_iter()
+        .find(|meta| meta.name.as_deref() == Some(name) && !meta.closed.load(Ordering::Acquire))
+}
+
+pub fn all() -> Vec<Arc<ConnMeta>> {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
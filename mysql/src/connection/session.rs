@@ -0,0 +1,101 @@
+use sqlx::{Executor as _, Row as _, mysql::MySqlConnection};
+
+/// MySQL's own default for `max_allowed_packet` before 8.0, used until the server tells us otherwise
+const DEFAULT_MAX_ALLOWED_PACKET: usize = 4 * 1024 * 1024;
+
+/// Which server it is, some error codes only exist on one of them or mean different things
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Flavor {
+    /// Not connected yet, only the codes both of them share are known
+    #[default]
+    Unknown,
+    MySql,
+    MariaDb,
+}
+
+/// Server/session variables that are loaded once per (re)connect
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub max_allowed_packet: usize,
+    /// `(major, minor, patch)` from `VERSION()`
+    pub version: (u32, u32, u32),
+    pub is_mariadb: bool,
+    /// `NO_BACKSLASH_ESCAPES` in `sql_mode`, `\` is a normal character inside strings then
+    pub no_backslash_escapes: bool,
+    /// `character_set_client`, the charset the server reads the statements we send in
+    pub charset: String,
+    /// Step between generated AUTO_INCREMENT ids
+    pub auto_increment_increment: u64,
+    /// `CONNECTION_ID()`, the id the server shows in the processlist and logs
+    pub connection_id: Option<u64>,
+}
+
+impl Default for SessionInfo {
+    fn default() -> Self {
+        Self {
+            max_allowed_packet: DEFAULT_MAX_ALLOWED_PACKET,
+            version: (0, 0, 0),
+            is_mariadb: false,
+            no_backslash_escapes: false,
+            charset: "utf8mb4".to_string(),
+            auto_increment_increment: 1,
+            connection_id: None,
+        }
+    }
+}
+
+impl SessionInfo {
+    pub async fn load(conn: &mut MySqlConnection) -> Result<Self, sqlx::Error> {
+        let row = conn
+            .fetch_one(concat!(
+                "SELECT CAST(@@max_allowed_packet AS UNSIGNED), VERSION(), @@sql_mode, ",
+                "@@character_set_client, CAST(@@auto_increment_increment AS UNSIGNED), ",
+                "CONNECTION_ID()"
+            ))
+            .await?;
+
+        let version_str = row.try_get::<String, _>(1)?;
+        Ok(Self {
+            max_allowed_packet: row.try_get::<u64, _>(0)? as usize,
+            version: parse_version(&version_str),
+            is_mariadb: version_str.to_ascii_lowercase().contains("mariadb"),
+            no_backslash_escapes: row
+                .try_get::<String, _>(2)?
+                .split(',')
+                .any(|mode| mode.eq_ignore_ascii_case("NO_BACKSLASH_ESCAPES")),
+            charset: row.try_get::<String, _>(3)?.to_ascii_lowercase(),
+            auto_increment_increment: row.try_get::<u64, _>(4)?,
+            connection_id: Some(row.try_get::<u64, _>(5)?),
+        })
+    }
+
+    pub fn flavor(&self) -> Flavor {
+        match (self.version, self.is_mariadb) {
+            ((0, 0, 0), _) => Flavor::Unknown,
+            (_, true) => Flavor::MariaDb,
+            (_, false) => Flavor::MySql,
+        }
+    }
+
+    /// `INSERT ... AS alias ON DUPLICATE KEY UPDATE` was added in MySQL 8.0.19, MariaDB doesn't have it
+    pub fn supports_insert_alias(&self) -> bool {
+        !self.is_mariadb && self.version >= (8, 0, 19)
+    }
+}
+
+/// Parses versions like `8.0.36`, `8.4.0-log` or `10.11.6-MariaDB-1:10.11.6+maria~ubu2204`
+fn parse_version(version: &str) -> (u32, u32, u32) {
+    // old MariaDB servers report themselves as `5.5.5-10.x.y-MariaDB` to keep old clients happy
+    let version = version.strip_prefix("5.5.5-").unwrap_or(version);
+
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .take(3)
+        .map(|p| p.parse::<u32>().unwrap_or(0));
+
+    (
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+        parts.next().unwrap_or(0),
+    )
+}
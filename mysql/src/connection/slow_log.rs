@@ -0,0 +1,206 @@
+use anyhow::{Result, bail};
+use gmodx::lua::{self, Function, LuaResultExt as _, Table};
+use sqlx::types::chrono::Local;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write as _,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    SLOW_QUERY_LOG_BACKUPS, SLOW_QUERY_LOG_DIR, SLOW_QUERY_LOG_MAX_BYTES, print_goobie_with_host,
+    query::{Query, QueryResult, fingerprint},
+};
+
+const LOG_NAME: &str = "slow_queries";
+
+/// Connections share the file, so only one write or rotation happens at a time
+static LOG_FILE: Mutex<()> = Mutex::new(());
+
+/// Set with `slow_query_ms`, queries that take longer than that to run are logged
+#[derive(Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    console: bool,
+    file: bool,
+    callback: Option<Function>,
+}
+
+struct SlowQuery<'a> {
+    host: &'a str,
+    duration_ms: f64,
+    rows: u64,
+    fingerprint: String,
+    query: &'a Query,
+}
+
+impl SlowQueryLog {
+    /// `slow_query_ms`, `slow_query_log` and `on_slow_query` options, None if `slow_query_ms`
+    /// isn't set
+    pub fn parse(state: &lua::State, opts: &Table) -> Result<Option<Self>> {
+        let Some(ms) = opts.get::<Option<f64>>(state, "slow_query_ms")? else {
+            return Ok(None);
+        };
+        if !(ms >= 0.0 && ms.is_finite()) {
+            bail!("slow_query_ms must be 0 or a positive number");
+        }
+
+        let log = opts
+            .get::<Option<lua::String>>(state, "slow_query_log")?
+            .map(|log| log.to_string());
+        let (console, file) = match log.as_deref() {
+            None | Some("console") => (true, false),
+            Some("file") => (false, true),
+            Some("both") => (true, true),
+            Some("none") => (false, false),
+            Some(log) => bail!(
+                "slow_query_log must be \"console\", \"file\", \"both\" or \"none\", got \"{log}\""
+            ),
+        };
+
+        Ok(Some(Self {
+            threshold: Duration::from_secs_f64(ms / 1000.0),
+            console,
+            file,
+            callback: opts.get(state, "on_slow_query")?,
+        }))
+    }
+
+    /// `elapsed` is how long it took to run, not counting the time it waited in the queue
+    pub fn record(&self, host: &str, query: &Query, elapsed: Duration) {
+        if elapsed < self.threshold {
+            return;
+        }
+
+        let slow = SlowQuery {
+            host,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            rows: query.result.as_ref().map_or(0, QueryResult::row_count),
+            fingerprint: fingerprint(&query.query),
+            query,
+        };
+
+        if self.console {
+            let trace = query
+                .trace
+                .as_ref()
+                .map_or(String::new(), |trace| format!("\n{trace}"));
+            print_goobie_with_host!(
+                host,
+                "Slow query took {:.1}ms ({} rows): {}{trace}",
+                slow.duration_ms,
+                slow.rows,
+                slow.fingerprint
+            );
+        }
+
+        if self.file {
+            let line = slow.log_line();
+            let host = host.to_string();
+            // this runs on the connection's task, the file is written on tokio's blocking threads
+            // so a slow disk doesn't hold up every connection's queries
+            gmodx::tokio_tasks::spawn(async move {
+                let res = tokio::task::spawn_blocking(move || append_to_file(&line)).await;
+                if let Ok(Err(e)) = res {
+                    print_goobie_with_host!(host, "Failed to write to the slow query log: {e}");
+                }
+            });
+        }
+
+        if let Some(callback) = self.callback.clone() {
+            let info = slow.to_owned_info();
+            gmodx::next_tick(move |state| {
+                callback.call::<()>(state, info.to_table(state)).log();
+            });
+        }
+    }
+}
+
+impl SlowQuery<'_> {
+    fn log_line(&self) -> String {
+        let mut line = format!(
+            "[{}] {} | {:.1}ms | {} rows | {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            self.host,
+            self.duration_ms,
+            self.rows,
+            self.fingerprint
+        );
+        if let Some(trace) = &self.query.trace {
+            for trace_line in trace.to_string().lines() {
+                line.push_str("    ");
+                line.push_str(trace_line);
+                line.push('\n');
+            }
+        }
+        line
+    }
+
+    /// What `on_slow_query` gets, the query itself stays on the handler's side
+    fn to_owned_info(&self) -> SlowQueryInfo {
+        SlowQueryInfo {
+            host: self.host.to_string(),
+            duration_ms: self.duration_ms,
+            rows: self.rows,
+            fingerprint: self.fingerprint.clone(),
+            query: self.query.query.clone(),
+            query_id: self.query.id,
+            trace: self.query.trace.clone(),
+        }
+    }
+}
+
+struct SlowQueryInfo {
+    host: String,
+    duration_ms: f64,
+    rows: u64,
+    fingerprint: String,
+    query: String,
+    query_id: u64,
+    trace: Option<lua::String>,
+}
+
+impl SlowQueryInfo {
+    fn to_table(&self, state: &lua::State) -> Table {
+        let table = state.create_table_with_capacity(0, 7);
+        table.raw_set(state, "host", self.host.as_str());
+        table.raw_set(state, "duration_ms", self.duration_ms);
+        table.raw_set(state, "rows", self.rows);
+        table.raw_set(state, "fingerprint", self.fingerprint.as_str());
+        table.raw_set(state, "query", self.query.as_str());
+        table.raw_set(state, "query_id", self.query_id);
+        if let Some(trace) = &self.trace {
+            table.raw_set(state, "trace", trace);
+        }
+        table
+    }
+}
+
+/// Appends to `slow_queries.txt`, once it's too big it becomes `slow_queries.1.txt` and the older
+/// ones move up by one, the oldest is dropped
+fn append_to_file(line: &str) -> std::io::Result<()> {
+    let _guard = LOG_FILE.lock().unwrap();
+    let dir = Path::new(SLOW_QUERY_LOG_DIR);
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{LOG_NAME}.txt"));
+    if fs::metadata(&path)
+        .is_ok_and(|meta| meta.len() + line.len() as u64 > SLOW_QUERY_LOG_MAX_BYTES)
+    {
+        for n in (1..SLOW_QUERY_LOG_BACKUPS).rev() {
+            let from = dir.join(format!("{LOG_NAME}.{n}.txt"));
+            if from.exists() {
+                fs::rename(from, dir.join(format!("{LOG_NAME}.{}.txt", n + 1)))?;
+            }
+        }
+        fs::rename(&path, dir.join(format!("{LOG_NAME}.1.txt")))?;
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(line.as_bytes())
+}
@@ -0,0 +1,220 @@
+use gmodx::lua::{self, Table};
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::Flavor;
+use crate::{
+    error::{ErrorKind, classify},
+    query::{Query, QueryResult, QueryType},
+};
+
+/// Latencies go in buckets on a log scale, 4 per doubling starting at 1µs, so a percentile is off
+/// by at most ~19%
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+/// Up to 2^40µs, about 12 days
+const LATENCY_BUCKETS: usize = 160;
+
+fn latency_bucket(elapsed: Duration) -> usize {
+    let micros = elapsed.as_micros() as f64;
+    if micros <= 1.0 {
+        return 0;
+    }
+    ((micros.log2() * BUCKETS_PER_DOUBLING) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+/// The upper bound of the bucket in milliseconds
+fn bucket_ms(bucket: usize) -> f64 {
+    2f64.powf((bucket + 1) as f64 / BUCKETS_PER_DOUBLING) / 1000.0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[derive(Clone)]
+struct LastError {
+    message: String,
+    kind: ErrorKind,
+    time: u64,
+}
+
+/// Counters for `Conn:Stats`, updated by the handler without locking anything, apart from the last
+/// error which is only touched when a query fails
+pub struct ConnStats {
+    queries: [AtomicU64; QueryType::ALL.len()],
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    reconnects: AtomicU64,
+    rows: AtomicU64,
+    bytes: AtomicU64,
+    /// Unix time of the last successful connect
+    connected_at: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+    last_error: Mutex<Option<LastError>>,
+}
+
+impl Default for ConnStats {
+    fn default() -> Self {
+        Self {
+            queries: [const { AtomicU64::new(0) }; QueryType::ALL.len()],
+            errors: [const { AtomicU64::new(0) }; ErrorKind::ALL.len()],
+            reconnects: AtomicU64::new(0),
+            rows: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            connected_at: AtomicU64::new(0),
+            latency: [const { AtomicU64::new(0) }; LATENCY_BUCKETS],
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+impl ConnStats {
+    /// A query that got to run, `elapsed` doesn't include the time it waited in the queue
+    pub fn record_query(&self, query: &Query, elapsed: Duration) {
+        self.queries[query.qtype as usize].fetch_add(1, Ordering::Relaxed);
+        self.latency[latency_bucket(elapsed)].fetch_add(1, Ordering::Relaxed);
+
+        let rows = query.result.as_ref().map_or(&[][..], QueryResult::rows);
+        let bytes: usize = rows
+            .iter()
+            .flatten()
+            .map(|column| column.value.byte_len())
+            .sum();
+        self.rows.fetch_add(rows.len() as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Any failed query, including ones that never got to run
+    pub fn record_error(&self, err: &anyhow::Error, flavor: Flavor) {
+        let (kind, _) = classify(err, flavor);
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(LastError {
+            message: err.to_string(),
+            kind,
+            time: unix_now(),
+        });
+    }
+
+    pub fn record_connect(&self) {
+        self.connected_at.store(unix_now(), Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            queries: self.queries.each_ref().map(load),
+            errors: self.errors.each_ref().map(load),
+            reconnects: load(&self.reconnects),
+            rows: load(&self.rows),
+            bytes: load(&self.bytes),
+            connected_at: load(&self.connected_at),
+            latency: self.latency.each_ref().map(load),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// The counters at one point in time, so the high priority connection's can be added in
+pub struct StatsSnapshot {
+    queries: [u64; QueryType::ALL.len()],
+    errors: [u64; ErrorKind::ALL.len()],
+    reconnects: u64,
+    rows: u64,
+    bytes: u64,
+    connected_at: u64,
+    latency: [u64; LATENCY_BUCKETS],
+    last_error: Option<LastError>,
+}
+
+impl StatsSnapshot {
+    /// Adds another connection's counters, the connect time is kept from this one
+    pub fn merge(&mut self, other: StatsSnapshot) {
+        let add = |into: &mut [u64], from: &[u64]| {
+            into.iter_mut()
+                .zip(from)
+                .for_each(|(into, from)| *into += from);
+        };
+        add(&mut self.queries, &other.queries);
+        add(&mut self.errors, &other.errors);
+        add(&mut self.latency, &other.latency);
+        self.reconnects += other.reconnects;
+        self.rows += other.rows;
+        self.bytes += other.bytes;
+        if let Some(err) = other.last_error
+            && self
+                .last_error
+                .as_ref()
+                .is_none_or(|last| err.time >= last.time)
+        {
+            self.last_error = Some(err);
+        }
+    }
+
+    /// `percentile` from 0 to 1, in milliseconds, None if nothing ran yet
+    fn latency_ms(&self, percentile: f64) -> Option<f64> {
+        let total: u64 = self.latency.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let target = ((total as f64 * percentile).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.latency.iter().enumerate().find_map(|(bucket, count)| {
+            seen += count;
+            (seen >= target).then(|| bucket_ms(bucket))
+        })
+    }
+
+    pub fn to_table(&self, state: &lua::State, queue_size: usize, connected: bool) -> Table {
+        let table = state.create_table_with_capacity(0, 9);
+
+        let queries = state.create_table_with_capacity(0, QueryType::ALL.len() as i32 + 1);
+        for (qtype, count) in QueryType::ALL.iter().zip(self.queries) {
+            queries.raw_set(state, qtype.as_str(), count);
+        }
+        queries.raw_set(state, "total", self.queries.iter().sum::<u64>());
+        table.raw_set(state, "queries", queries);
+
+        let errors = state.create_table_with_capacity(0, ErrorKind::ALL.len() as i32 + 1);
+        for (kind, count) in ErrorKind::ALL.iter().zip(self.errors) {
+            errors.raw_set(state, kind.as_str(), count);
+        }
+        errors.raw_set(state, "total", self.errors.iter().sum::<u64>());
+        table.raw_set(state, "errors", errors);
+
+        table.raw_set(state, "reconnects", self.reconnects);
+        table.raw_set(state, "rows", self.rows);
+        table.raw_set(state, "bytes", self.bytes);
+        table.raw_set(state, "queue_size", queue_size);
+        if connected && self.connected_at > 0 {
+            table.raw_set(state, "connected_since", self.connected_at);
+        }
+
+        if let Some(err) = &self.last_error {
+            let last_error = state.create_table_with_capacity(0, 3);
+            last_error.raw_set(state, "message", err.message.as_str());
+            last_error.raw_set(state, "kind", err.kind.as_str());
+            last_error.raw_set(state, "time", err.time);
+            table.raw_set(state, "last_error", last_error);
+        }
+
+        let latency = state.create_table_with_capacity(0, 3);
+        for (name, percentile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+            if let Some(ms) = self.latency_ms(percentile) {
+                latency.raw_set(state, name, ms);
+            }
+        }
+        table.raw_set(state, "latency", latency);
+
+        table
+    }
+}
@@ -1,10 +1,15 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use sqlx::mysql::MySqlConnectOptions;
-use std::sync::{
-    Arc,
-    atomic::{AtomicUsize, Ordering},
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
-use tokio::sync::mpsc;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
 
 use gmodx::{
     lua::{self, Function, Table},
@@ -12,18 +17,26 @@ use gmodx::{
 };
 
 use crate::{
-    query,
+    error::{Closed, QueueFull, ShuttingDown},
+    query, shutdown,
     state::{AtomicState, State},
 };
 
-use super::{handler, options};
+use super::{
+    SessionInfo, handler,
+    lanes::{Lanes, OverflowPolicy},
+    options, reconnect, registry,
+    slow_log::SlowQueryLog,
+    stats::ConnStats,
+};
 
 pub enum ConnMessage {
     Connect(Option<Function>),
     Disconnect(Option<Function>),
-    Query(query::Query),
+    Query(Box<query::Query>),
     Ping(Option<Function>),
-    Close,
+    /// Disconnects, fails everything still queued and stops the handler
+    Close(Option<Function>),
 }
 
 pub struct ConnMeta {
@@ -34,34 +47,235 @@ pub struct ConnMeta {
     // transaction would think it's still in a transaction
     pub id: AtomicUsize,
     pub state: AtomicState,
+    /// Set with the `name` option, so other addons can find it with `GetConn`
+    pub name: Option<String>,
     pub opts: MySqlConnectOptions,
+    pub session: RwLock<SessionInfo>,
+    /// Defaults for queries that don't set their own
+    pub settings: query::QuerySettings,
+    /// Set by `Close`, messages that are still queued get failed instead of run
+    pub closed: AtomicBool,
+    /// Queries that were sent and haven't had their callback run yet
+    pub pending_queries: Arc<AtomicUsize>,
+    /// Cancel tokens of pending queries by their id, for `Cancel`
+    pub queries: Mutex<HashMap<u64, CancellationToken>>,
+    /// Messages waiting for the handler
+    pub queue: Mutex<Lanes>,
+    /// Wakes the handler when something is queued
+    pub queued: Notify,
+    /// Wakes `*Sync` calls waiting for room in the queue when the handler takes something
+    pub dequeued: Condvar,
+    pub limits: QueueLimits,
+    pub stats: ConnStats,
+    pub slow_log: Option<SlowQueryLog>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueLimits {
+    /// 0 for no limit
+    pub max_queue: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl QueueLimits {
+    fn parse(state: &lua::State, opts: &Table) -> Result<Self> {
+        let mut limits = Self::default();
+        if let Some(max_queue) = opts.get::<Option<usize>>(state, "max_queue")? {
+            limits.max_queue = max_queue;
+        }
+        if let Some(policy) = opts.get::<Option<lua::String>>(state, "queue_overflow")? {
+            limits.overflow = OverflowPolicy::parse(&policy.to_string())?;
+        }
+        Ok(limits)
+    }
+}
+
+impl ConnMeta {
+    fn new(
+        name: Option<String>,
+        opts: MySqlConnectOptions,
+        settings: query::QuerySettings,
+        limits: QueueLimits,
+        slow_log: Option<SlowQueryLog>,
+        pending_queries: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            id: AtomicUsize::new(0),
+            state: AtomicState::new(State::NotConnected),
+            name,
+            opts,
+            session: RwLock::new(SessionInfo::default()),
+            settings,
+            closed: AtomicBool::new(false),
+            pending_queries,
+            queries: Mutex::new(HashMap::new()),
+            queue: Mutex::new(Lanes::default()),
+            queued: Notify::new(),
+            dequeued: Condvar::new(),
+            limits,
+            stats: ConnStats::default(),
+            slow_log,
+        }
+    }
+
+    /// Queues a message for the handler, a query that doesn't fit in `max_queue` is dealt with by
+    /// the overflow policy
+    fn push(&self, msg: ConnMessage) {
+        let mut queue = self.queue.lock().unwrap();
+        let max_queue = self.limits.max_queue;
+
+        if let ConnMessage::Query(query) = &msg
+            && max_queue > 0
+            && queue.queries() >= max_queue
+        {
+            match self.limits.overflow {
+                OverflowPolicy::Reject => {}
+                OverflowPolicy::DropLow => {
+                    if let Some(ConnMessage::Query(dropped)) = queue.pop_oldest_low() {
+                        reconnect::fail_query(self, *dropped, QueueFull(max_queue).into());
+                    }
+                }
+                // the handler doesn't need the main thread to take from the queue, so this
+                // can't deadlock
+                OverflowPolicy::Block if query.sync.is_some() => {
+                    let timeout = query
+                        .timeout
+                        .unwrap_or(Duration::from_secs(crate::SYNC_QUERY_TIMEOUT));
+                    queue = self
+                        .dequeued
+                        .wait_timeout_while(queue, timeout, |queue| queue.queries() >= max_queue)
+                        .unwrap()
+                        .0;
+                }
+                OverflowPolicy::Block => {}
+            }
+
+            if queue.queries() >= max_queue {
+                drop(queue);
+                if let ConnMessage::Query(query) = msg {
+                    reconnect::fail_query(self, *query, QueueFull(max_queue).into());
+                }
+                return;
+            }
+        }
+
+        queue.push(msg);
+        drop(queue);
+        self.queued.notify_one();
+    }
+
+    /// What the handler runs next
+    pub fn pop(&self) -> Option<ConnMessage> {
+        let msg = self.queue.lock().unwrap().pop();
+        if msg.is_some() {
+            self.dequeued.notify_all();
+        }
+        msg
+    }
+
+    /// Queries waiting to be run, not counting the one that's running
+    pub fn queue_size(&self) -> usize {
+        self.queue.lock().unwrap().queries()
+    }
+}
+
+/// Counts a query as pending until it's dropped, which is after its callback ran or it got thrown away
+pub struct PendingGuard {
+    meta: Arc<ConnMeta>,
+    query_id: u64,
+}
+
+impl PendingGuard {
+    fn new(meta: &Arc<ConnMeta>, query: &query::Query) -> Self {
+        meta.pending_queries.fetch_add(1, Ordering::AcqRel);
+        meta.queries
+            .lock()
+            .unwrap()
+            .insert(query.id, query.cancel.clone());
+        Self {
+            meta: meta.clone(),
+            query_id: query.id,
+        }
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.meta.pending_queries.fetch_sub(1, Ordering::AcqRel);
+        self.meta.queries.lock().unwrap().remove(&self.query_id);
+    }
+}
+
+/// A second connection with its own handler for `priority = "high"` queries, so they don't wait
+/// behind whatever the main one is running, set with the `high_priority_connection` option
+struct HighLane {
+    meta: Arc<ConnMeta>,
 }
 
 pub struct Conn {
     pub meta: Arc<ConnMeta>,
-    pub sender: mpsc::UnboundedSender<ConnMessage>,
+    high_lane: Option<HighLane>,
+    heartbeat: CancellationToken,
+}
+
+fn spawn_handler(meta: &Arc<ConnMeta>) {
+    let meta = meta.clone();
+    gmodx::tokio_tasks::spawn(async move {
+        handler::handle_messages(meta).await;
+    });
 }
 
 impl Conn {
     pub fn new(state: &lua::State, opts: Table) -> Result<Self> {
+        let settings = query::QuerySettings::parse(state, &opts, &Default::default())?;
+        let limits = QueueLimits::parse(state, &opts)?;
+        let slow_log = SlowQueryLog::parse(state, &opts)?;
+        let name = opts
+            .get::<Option<lua::String>>(state, "name")?
+            .map(|name| name.to_string());
+        if let Some(name) = &name
+            && registry::find(name).is_some()
+        {
+            bail!(
+                "a connection named \"{name}\" already exists, use goobie_mysql.GetConn to share it"
+            );
+        }
+        let high_priority_connection = opts
+            .get::<Option<bool>>(state, "high_priority_connection")?
+            .unwrap_or(false);
         let opts = options::parse(state, opts)?;
-        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let meta = Arc::new(ConnMeta::new(
+            name,
+            opts,
+            settings,
+            limits,
+            slow_log,
+            Arc::new(AtomicUsize::new(0)),
+        ));
+        spawn_handler(&meta);
+        // shares the pending count, so shutdown waits for its queries too
+        let high_lane = high_priority_connection.then(|| {
+            let meta = Arc::new(ConnMeta::new(
+                None,
+                meta.opts.clone(),
+                meta.settings.clone(),
+                limits,
+                meta.slow_log.clone(),
+                meta.pending_queries.clone(),
+            ));
+            spawn_handler(&meta);
+            HighLane { meta }
+        });
 
         let conn = Conn {
-            meta: Arc::new(ConnMeta {
-                id: AtomicUsize::new(0),
-                state: AtomicState::new(State::NotConnected),
-                opts,
-            }),
-            sender,
+            meta,
+            high_lane,
+            heartbeat: CancellationToken::new(),
         };
 
-        let meta = conn.meta.clone();
-        gmodx::tokio_tasks::spawn(async move {
-            handler::handle_messages(receiver, meta).await;
-        });
-
         conn.spawn_ping_heartbeat();
+        registry::register(&conn.meta);
 
         Ok(conn)
     }
@@ -81,15 +295,96 @@ impl Conn {
         gmodx::flush_next_tick(state);
     }
 
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.meta.closed.load(Ordering::Acquire)
+    }
+
+    /// Queries waiting to be run, on both connections if there's a high priority one
+    pub fn queue_size(&self) -> usize {
+        self.meta.queue_size()
+            + self
+                .high_lane
+                .as_ref()
+                .map_or(0, |lane| lane.meta.queue_size())
+    }
+
+    /// Counters of both connections if there's a high priority one
+    pub fn stats(&self, state: &lua::State) -> Table {
+        let mut stats = self.meta.stats.snapshot();
+        if let Some(lane) = &self.high_lane {
+            stats.merge(lane.meta.stats.snapshot());
+        }
+        stats.to_table(state, self.queue_size(), self.state() == State::Connected)
+    }
+
+    pub fn send(&self, mut msg: ConnMessage) -> Result<()> {
+        if self.is_closed() {
+            bail!(Closed);
+        }
+        if shutdown::is_shutting_down() {
+            bail!(ShuttingDown);
+        }
+        if let ConnMessage::Query(query) = &mut msg {
+            query.pending = Some(PendingGuard::new(&self.meta, query));
+        }
+
+        if let Some(lane) = &self.high_lane {
+            match &msg {
+                ConnMessage::Query(query) if query.priority == query::Priority::High => {
+                    lane.meta.push(msg);
+                    return Ok(());
+                }
+                // callbacks only get called for the main connection
+                ConnMessage::Connect(_) => lane.meta.push(ConnMessage::Connect(None)),
+                ConnMessage::Disconnect(_) => lane.meta.push(ConnMessage::Disconnect(None)),
+                _ => {}
+            }
+        }
+
+        self.meta.push(msg);
+        Ok(())
+    }
+
+    /// Returns false if there's no pending query with that id
+    pub fn cancel(&self, query_id: u64) -> bool {
+        let token = self.meta.queries.lock().unwrap().get(&query_id).cloned();
+        match token {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn close(&self, callback: Option<Function>) -> Result<()> {
+        if self.meta.closed.swap(true, Ordering::AcqRel) {
+            bail!(Closed);
+        }
+        self.heartbeat.cancel();
+        if let Some(lane) = &self.high_lane {
+            lane.meta.closed.store(true, Ordering::Release);
+            lane.meta.push(ConnMessage::Close(None));
+        }
+        self.meta.push(ConnMessage::Close(callback));
+        Ok(())
+    }
+
     fn spawn_ping_heartbeat(&self) {
-        let sender = self.sender.clone();
+        let metas: Vec<_> = std::iter::once(self.meta.clone())
+            .chain(self.high_lane.as_ref().map(|lane| lane.meta.clone()))
+            .collect();
+        let heartbeat = self.heartbeat.clone();
         spawn_untracked(async move {
             loop {
-                // Try to send; if the receiver closed, exit.
-                if sender.send(ConnMessage::Ping(None)).is_err() {
-                    break;
+                for meta in &metas {
+                    meta.push(ConnMessage::Ping(None));
+                }
+                tokio::select! {
+                    _ = heartbeat.cancelled() => break,
+                    _ = tokio::time::sleep(std::time::Duration::from_secs(crate::PING_INTERVAL)) => {}
                 }
-                tokio::time::sleep(std::time::Duration::from_secs(crate::PING_INTERVAL)).await;
             }
         });
     }
@@ -97,16 +392,19 @@ impl Conn {
 
 impl Drop for Conn {
     fn drop(&mut self) {
-        let _ = self.sender.send(ConnMessage::Disconnect(None));
-        let _ = self.sender.send(ConnMessage::Close);
+        let _ = self.close(None);
     }
 }
 
 impl std::fmt::Display for Conn {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Goobie MySQL Connection [")?;
+        if let Some(name) = &self.meta.name {
+            write!(f, "Name: {name} | ")?;
+        }
         write!(
             f,
-            "Goobie MySQL Connection [ID: {} | IP: {} | Port: {} | State: {}]",
+            "ID: {} | IP: {} | Port: {} | State: {}]",
             self.id(),
             self.meta.opts.get_host(),
             self.meta.opts.get_port(),
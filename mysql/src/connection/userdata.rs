@@ -1,9 +1,19 @@
+use std::{sync::mpsc, time::Duration};
+
 use anyhow::Result;
-use gmodx::lua::{self, Function, ObjectLike as _, Table, UserData, UserDataRef};
+use gmodx::lua::{
+    self, AnyUserData, Function, ObjectLike as _, Table, Thread, UserData, UserDataRef,
+};
 
-use crate::query;
+use crate::{
+    error::{SyncTimeout, to_error_table},
+    query,
+};
 
-use super::types::{Conn, ConnMessage};
+use super::{
+    reconnect::{ResultArgs, sync_result},
+    types::{Conn, ConnMessage},
+};
 
 impl UserData for Conn {
     fn meta_methods(methods: &mut lua::Methods) {
@@ -15,42 +25,165 @@ impl UserData for Conn {
     fn methods(methods: &mut lua::Methods) {
         methods.add(
             c"Start",
-            |_: &lua::State, conn: UserDataRef<Conn>, callback: Function| {
-                conn.borrow()
-                    .sender
-                    .send(ConnMessage::Connect(Some(callback)))
-                    .ok();
+            |_: &lua::State, conn: UserDataRef<Conn>, callback: Function| -> Result<()> {
+                conn.borrow().send(ConnMessage::Connect(Some(callback)))
             },
         );
 
         methods.add(
             c"Disconnect",
-            |_: &lua::State, conn: UserDataRef<Conn>, callback: Option<Function>| {
-                conn.borrow()
-                    .sender
-                    .send(ConnMessage::Disconnect(callback))
-                    .ok();
+            |_: &lua::State, conn: UserDataRef<Conn>, callback: Option<Function>| -> Result<()> {
+                conn.borrow().send(ConnMessage::Disconnect(callback))
+            },
+        );
+
+        methods.add(
+            c"Close",
+            |_: &lua::State, conn: UserDataRef<Conn>, callback: Option<Function>| -> Result<()> {
+                conn.borrow().close(callback)
             },
         );
 
+        methods.add(
+            c"Cancel",
+            |_: &lua::State, conn: UserDataRef<Conn>, query_id: u64| conn.borrow().cancel(query_id),
+        );
+
+        methods.add(c"QueueSize", |_: &lua::State, conn: UserDataRef<Conn>| {
+            conn.borrow().queue_size()
+        });
+
+        methods.add(c"Name", |_: &lua::State, conn: UserDataRef<Conn>| {
+            conn.borrow().meta.name.clone()
+        });
+
+        methods.add(c"Stats", |state: &lua::State, conn: UserDataRef<Conn>| {
+            conn.borrow().stats(state)
+        });
+
+        methods.add(c"IsClosed", |_: &lua::State, conn: UserDataRef<Conn>| {
+            conn.borrow().is_closed()
+        });
+
         methods.add(c"State", |_: &lua::State, conn: UserDataRef<Conn>| {
             conn.borrow().state() as usize
         });
 
         methods.add(
             c"Ping",
-            |_: &lua::State, conn: UserDataRef<Conn>, callback: Function| {
-                conn.borrow()
-                    .sender
-                    .send(ConnMessage::Ping(Some(callback)))
-                    .ok();
+            |_: &lua::State, conn: UserDataRef<Conn>, callback: Function| -> Result<()> {
+                conn.borrow().send(ConnMessage::Ping(Some(callback)))
+            },
+        );
+
+        for (name, sync_name, qtype) in [
+            (c"Run", c"RunSync", query::QueryType::Run),
+            (c"Execute", c"ExecuteSync", query::QueryType::Execute),
+            (
+                c"ExecuteReturning",
+                c"ExecuteReturningSync",
+                query::QueryType::ExecuteReturning,
+            ),
+            (c"FetchOne", c"FetchOneSync", query::QueryType::FetchOne),
+            (c"Fetch", c"FetchSync", query::QueryType::FetchAll),
+        ] {
+            methods.add(name, create_query_func(qtype));
+            methods.add(sync_name, create_sync_query_func(qtype));
+        }
+
+        methods.add(
+            c"Await",
+            |state: &lua::State,
+             conn: UserDataRef<Conn>,
+             query_type: lua::String,
+             query: lua::String,
+             opts: Option<Table>,
+             coroutine: Thread|
+             -> Result<()> {
+                let qtype = query::QueryType::from_method(&query_type.to_string())?;
+                let mut query = new_query(state, &conn, query.to_string(), qtype, opts)?;
+                query.coroutine = Some(coroutine);
+                conn.borrow().send(ConnMessage::Query(Box::new(query)))
+            },
+        );
+
+        methods.add(
+            c"BulkInsert",
+            |state: &lua::State,
+             conn: UserDataRef<Conn>,
+             table: lua::String,
+             columns: Table,
+             rows: Table,
+             opts: Option<Table>|
+             -> Result<Option<AnyUserData>> {
+                let query = bulk_insert_query(state, &conn, table, columns, rows, opts)?;
+                send_query(&conn, query)
+            },
+        );
+
+        methods.add(
+            c"BulkInsertSync",
+            |state: &lua::State,
+             conn: UserDataRef<Conn>,
+             table: lua::String,
+             columns: Table,
+             rows: Table,
+             opts: Option<Table>|
+             -> Result<ResultArgs> {
+                let query = bulk_insert_query(state, &conn, table, columns, rows, opts)?;
+                run_sync(state, &conn, query)
+            },
+        );
+
+        methods.add(
+            c"UpsertQuery",
+            |state: &lua::State,
+             conn: UserDataRef<Conn>,
+             table: lua::String,
+             opts: Table|
+             -> Result<(Option<String>, Option<Vec<query::Param>>)> {
+                let bulk = query::BulkInsert::upsert(state, table, &opts)?;
+
+                if opts.get::<Option<bool>>(state, "return_query")? == Some(true) {
+                    // uses whatever we know about the server so far, VALUES() syntax if not connected yet
+                    let session = conn.borrow().meta.session.read().unwrap().clone();
+                    let (query, params) = bulk.into_statement(&session)?;
+                    return Ok((Some(query), Some(params)));
+                }
+
+                let query = bulk_query(state, &conn, bulk, Some(opts))?;
+                conn.borrow().send(ConnMessage::Query(Box::new(query)))?;
+                Ok((None, None))
             },
         );
 
-        methods.add(c"Run", create_query_func(query::QueryType::Run));
-        methods.add(c"Execute", create_query_func(query::QueryType::Execute));
-        methods.add(c"FetchOne", create_query_func(query::QueryType::FetchOne));
-        methods.add(c"Fetch", create_query_func(query::QueryType::FetchAll));
+        methods.add(
+            c"UpsertQuerySync",
+            |state: &lua::State,
+             conn: UserDataRef<Conn>,
+             table: lua::String,
+             opts: Table|
+             -> Result<ResultArgs> {
+                let bulk = query::BulkInsert::upsert(state, table, &opts)?;
+                let query = bulk_query(state, &conn, bulk, Some(opts))?;
+                run_sync(state, &conn, query)
+            },
+        );
+
+        methods.add(
+            c"Escape",
+            |state: &lua::State, conn: UserDataRef<Conn>, value: lua::Value| -> Result<String> {
+                let session = conn.borrow().meta.session.read().unwrap().clone();
+                query::escape_value(state, value, &session)
+            },
+        );
+
+        methods.add(
+            c"EscapeIdentifier",
+            |_: &lua::State, _: UserDataRef<Conn>, name: lua::String| {
+                query::escape_identifier(&name)
+            },
+        );
 
         methods.add(c"ID", |_: &lua::State, conn: UserDataRef<Conn>| {
             conn.borrow().id()
@@ -72,15 +205,109 @@ impl UserData for Conn {
 
 fn create_query_func(
     qtype: query::QueryType,
-) -> impl Fn(&lua::State, UserDataRef<Conn>, lua::String, Option<Table>) -> Result<()> {
+) -> impl Fn(&lua::State, UserDataRef<Conn>, lua::String, Option<Table>) -> Result<Option<AnyUserData>>
+{
     move |state: &lua::State,
           conn: UserDataRef<Conn>,
           query: lua::String,
           opts: Option<Table>|
-          -> Result<()> {
-        let on_error = conn.as_any().get::<Option<Function>>(state, "on_error")?;
-        let query = query::Query::new(state, query.to_string(), qtype, on_error, opts)?;
-        conn.borrow().sender.send(ConnMessage::Query(query)).ok();
-        Ok(())
+          -> Result<Option<AnyUserData>> {
+        let query = new_query(state, &conn, query.to_string(), qtype, opts)?;
+        send_query(&conn, query)
     }
 }
+
+/// Returns the query's promise, if it has one
+fn send_query(conn: &UserDataRef<Conn>, query: query::Query) -> Result<Option<AnyUserData>> {
+    let promise = query.promise.as_ref().map(|promise| promise.to_any());
+    conn.borrow().send(ConnMessage::Query(Box::new(query)))?;
+    Ok(promise)
+}
+
+fn create_sync_query_func(
+    qtype: query::QueryType,
+) -> impl Fn(&lua::State, UserDataRef<Conn>, lua::String, Option<Table>) -> Result<ResultArgs> {
+    move |state: &lua::State,
+          conn: UserDataRef<Conn>,
+          query: lua::String,
+          opts: Option<Table>|
+          -> Result<ResultArgs> {
+        let query = new_query(state, &conn, query.to_string(), qtype, opts)?;
+        run_sync(state, &conn, query)
+    }
+}
+
+/// Blocks until the connection is done with this query, other queries' callbacks aren't run
+/// meanwhile, they get their turn on the next tick as usual
+fn run_sync(
+    state: &lua::State,
+    conn: &UserDataRef<Conn>,
+    mut query: query::Query,
+) -> Result<ResultArgs> {
+    let timeout = query
+        .timeout
+        .unwrap_or(Duration::from_secs(crate::SYNC_QUERY_TIMEOUT));
+    let (sender, receiver) = mpsc::sync_channel(1);
+    query.sync = Some(sender);
+    conn.borrow().send(ConnMessage::Query(Box::new(query)))?;
+
+    Ok(match receiver.recv_timeout(timeout) {
+        Ok((query, connection_id)) => sync_result(state, &query, connection_id),
+        Err(_) => (
+            Some(to_error_table(state, &SyncTimeout(timeout).into())),
+            None,
+            None,
+        ),
+    })
+}
+
+fn bulk_insert_query(
+    state: &lua::State,
+    conn: &UserDataRef<Conn>,
+    table: lua::String,
+    columns: Table,
+    rows: Table,
+    opts: Option<Table>,
+) -> Result<query::Query> {
+    let bulk = query::BulkInsert::new(state, table, columns, rows, opts.as_ref())?;
+    bulk_query(state, conn, bulk, opts)
+}
+
+fn bulk_query(
+    state: &lua::State,
+    conn: &UserDataRef<Conn>,
+    bulk: query::BulkInsert,
+    opts: Option<Table>,
+) -> Result<query::Query> {
+    let mut query = new_query(
+        state,
+        conn,
+        bulk.to_string(),
+        query::QueryType::Execute,
+        opts,
+    )?;
+    query.bulk = Some(bulk);
+    Ok(query)
+}
+
+fn new_query(
+    state: &lua::State,
+    conn: &UserDataRef<Conn>,
+    query: String,
+    qtype: query::QueryType,
+    opts: Option<Table>,
+) -> Result<query::Query> {
+    let on_error = conn.as_any().get::<Option<Function>>(state, "on_error")?;
+    let conn = conn.borrow();
+    // the session of the connection it's sent on, for the server's sql_mode
+    let session = conn.meta.session.read().unwrap().clone();
+    query::Query::new(
+        state,
+        query,
+        qtype,
+        on_error,
+        &conn.meta.settings,
+        &session,
+        opts,
+    )
+}
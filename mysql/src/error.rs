@@ -1,20 +1,394 @@
+use std::{fmt, time::Duration};
+
 use gmodx::lua::{self, Table};
 use sqlx::mysql::MySqlDatabaseError;
 
-use crate::GOOBIE_MYSQL_TABLE_NAME;
+use crate::{
+    GOOBIE_MYSQL_TABLE_NAME,
+    connection::Flavor,
+    error_codes,
+    query::{Query, WarningsError},
+};
+
+/// A query or ping was sent while there's no connection, e.g. while reconnecting
+#[derive(Debug)]
+pub struct NotConnected;
+
+impl fmt::Display for NotConnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection is not open")
+    }
+}
+
+impl std::error::Error for NotConnected {}
+
+/// New work was sent while the server is shutting down
+#[derive(Debug)]
+pub struct ShuttingDown;
+
+impl fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("server is shutting down")
+    }
+}
+
+impl std::error::Error for ShuttingDown {}
+
+/// A `*Sync` call stopped waiting, the query itself is left running
+#[derive(Debug)]
+pub struct SyncTimeout(pub Duration);
+
+impl fmt::Display for SyncTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:.1} seconds waiting for the query, it may still complete",
+            self.0.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for SyncTimeout {}
+
+/// `Conn:Close()` was called, nothing will run on this connection again
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection is closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// The connection already had `max_queue` queries waiting
+#[derive(Debug)]
+pub struct QueueFull(pub usize);
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "query queue is full ({} queries waiting)", self.0)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// The query's promise was cancelled before it got a result
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("query was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A query's callback threw, holds the lua error message
+#[derive(Debug)]
+pub struct CallbackError(pub String);
+
+impl fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "query callback errored: {}", self.0)
+    }
+}
+
+impl std::error::Error for CallbackError {}
+
+#[derive(Debug)]
+pub enum ConnectCause {
+    Error(sqlx::Error),
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct ConnectAttempt {
+    /// `host:port`, or the socket path
+    pub host: String,
+    pub duration: Duration,
+    pub cause: ConnectCause,
+}
+
+impl fmt::Display for ConnectAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cause {
+            ConnectCause::Error(e) => write!(f, "{e}"),
+            ConnectCause::TimedOut => write!(
+                f,
+                "timed out after {:.1} seconds",
+                self.duration.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Every attempt failed, the last one is the most relevant
+#[derive(Debug)]
+pub struct ConnectError(pub Vec<ConnectAttempt>);
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(last) = self.0.last() else {
+            return f.write_str("failed to connect");
+        };
+        write!(
+            f,
+            "failed to connect to {} after {} attempt{}: {last}",
+            last.host,
+            self.0.len(),
+            if self.0.len() == 1 { "" } else { "s" }
+        )
+    }
+}
 
-fn write_mysql_error_fields(state: &lua::State, err: &MySqlDatabaseError, out: &Table) -> String {
+impl std::error::Error for ConnectError {}
+
+impl ConnectError {
+    fn write_fields(&self, state: &lua::State, flavor: Flavor, out: &Table) {
+        let attempts = state.create_table_with_capacity(self.0.len() as i32, 0);
+        for (idx, attempt) in self.0.iter().enumerate() {
+            let attempt_table = state.create_table_with_capacity(0, 5);
+            attempt_table.raw_set(state, "host", &attempt.host);
+            attempt_table.raw_set(state, "duration", attempt.duration.as_secs_f64());
+            match &attempt.cause {
+                ConnectCause::Error(e) => write_sqlx_error(state, e, flavor, &attempt_table),
+                ConnectCause::TimedOut => {
+                    attempt_table.raw_set(state, "message", attempt.to_string())
+                }
+            }
+            attempts.raw_set(state, idx as i32 + 1, attempt_table);
+        }
+        out.raw_set(state, "attempts", attempts);
+        out.raw_set(state, "message", self.to_string());
+    }
+}
+
+/// What went wrong, so lua can branch on it without knowing every error code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Connection,
+    Timeout,
+    Syntax,
+    /// A table, column or database that doesn't exist, the query itself is fine
+    Schema,
+    Constraint,
+    Duplicate,
+    Deadlock,
+    Permission,
+    Data,
+    Protocol,
+    Internal,
+    /// The query worked, its callback didn't
+    Callback,
+    Cancelled,
+    /// Too much queued, see `max_queue`
+    Overloaded,
+}
+
+impl ErrorKind {
+    pub const ALL: [Self; 14] = [
+        ErrorKind::Connection,
+        ErrorKind::Timeout,
+        ErrorKind::Syntax,
+        ErrorKind::Schema,
+        ErrorKind::Constraint,
+        ErrorKind::Duplicate,
+        ErrorKind::Deadlock,
+        ErrorKind::Permission,
+        ErrorKind::Data,
+        ErrorKind::Protocol,
+        ErrorKind::Internal,
+        ErrorKind::Callback,
+        ErrorKind::Cancelled,
+        ErrorKind::Overloaded,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Connection => "connection",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Syntax => "syntax",
+            ErrorKind::Schema => "schema",
+            ErrorKind::Constraint => "constraint",
+            ErrorKind::Duplicate => "duplicate",
+            ErrorKind::Deadlock => "deadlock",
+            ErrorKind::Permission => "permission",
+            ErrorKind::Data => "data",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Internal => "internal",
+            ErrorKind::Callback => "callback",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::Overloaded => "overloaded",
+        }
+    }
+
+    /// Codes only one of the servers has are only looked at once we know which one it is
+    fn from_code(code: u16, flavor: Flavor) -> Self {
+        use crate::error_codes::*;
+        match (flavor, code) {
+            (Flavor::MySql, ER_CLIENT_INTERACTION_TIMEOUT)
+            | (Flavor::MariaDb, ER_CONNECTION_KILLED) => ErrorKind::Connection,
+            (Flavor::MySql, ER_QUERY_TIMEOUT) | (Flavor::MariaDb, ER_STATEMENT_TIMEOUT) => {
+                ErrorKind::Timeout
+            }
+            (Flavor::MySql, ER_CHECK_CONSTRAINT_VIOLATED)
+            | (Flavor::MariaDb, ER_CONSTRAINT_FAILED) => ErrorKind::Constraint,
+            (_, code) => Self::from_shared_code(code),
+        }
+    }
+
+    fn from_shared_code(code: u16) -> Self {
+        use crate::error_codes::*;
+        match code {
+            ER_CON_COUNT_ERROR
+            | ER_SERVER_SHUTDOWN
+            | CR_CONNECTION_ERROR
+            | CR_CONN_HOST_ERROR
+            | CR_SERVER_GONE_ERROR
+            | CR_SERVER_LOST
+            | CR_SERVER_LOST_EXTENDED => ErrorKind::Connection,
+            ER_LOCK_WAIT_TIMEOUT => ErrorKind::Timeout,
+            ER_PARSE_ERROR | ER_SYNTAX_ERROR | ER_WRONG_VALUE_COUNT_ON_ROW => ErrorKind::Syntax,
+            ER_BAD_FIELD_ERROR | ER_NO_SUCH_TABLE | ER_BAD_TABLE_ERROR | ER_BAD_DB_ERROR
+            | ER_NO_DB_ERROR => ErrorKind::Schema,
+            ER_DUP_ENTRY | ER_DUP_ENTRY_WITH_KEY_NAME | ER_DUP_KEY => ErrorKind::Duplicate,
+            ER_ROW_IS_REFERENCED_2
+            | ER_NO_REFERENCED_ROW_2
+            | ER_NO_REFERENCED_ROW
+            | ER_ROW_IS_REFERENCED
+            | ER_BAD_NULL_ERROR
+            | ER_NO_DEFAULT_FOR_FIELD => ErrorKind::Constraint,
+            ER_LOCK_DEADLOCK => ErrorKind::Deadlock,
+            ER_DBACCESS_DENIED_ERROR
+            | ER_ACCESS_DENIED_ERROR
+            | ER_TABLEACCESS_DENIED_ERROR
+            | ER_COLUMNACCESS_DENIED_ERROR
+            | ER_SPECIFIC_ACCESS_DENIED_ERROR
+            | ER_PROCACCESS_DENIED_ERROR => ErrorKind::Permission,
+            ER_WARN_DATA_OUT_OF_RANGE
+            | WARN_DATA_TRUNCATED
+            | ER_TRUNCATED_WRONG_VALUE
+            | ER_TRUNCATED_WRONG_VALUE_FOR_FIELD
+            | ER_ILLEGAL_VALUE_FOR_TYPE
+            | ER_DATA_TOO_LONG
+            | ER_DATA_OUT_OF_RANGE
+            | ER_DIVISION_BY_ZERO => ErrorKind::Data,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Returns the kind of the error and whether running the same thing again could work
+/// `flavor` is the server the error came from, `Flavor::Unknown` if it's not known
+pub fn classify(err: &anyhow::Error, flavor: Flavor) -> (ErrorKind, bool) {
+    if err.is::<NotConnected>() {
+        return (ErrorKind::Connection, true);
+    }
+    if err.is::<Closed>() || err.is::<ShuttingDown>() {
+        return (ErrorKind::Connection, false);
+    }
+    if err.is::<WarningsError>() {
+        return (ErrorKind::Data, false);
+    }
+    if err.is::<SyncTimeout>() {
+        return (ErrorKind::Timeout, false);
+    }
+    if err.is::<CallbackError>() {
+        return (ErrorKind::Callback, false);
+    }
+    if err.is::<Cancelled>() {
+        return (ErrorKind::Cancelled, false);
+    }
+    if err.is::<QueueFull>() {
+        return (ErrorKind::Overloaded, true);
+    }
+    if let Some(connect_err) = err.downcast_ref::<ConnectError>() {
+        return match connect_err.0.last().map(|attempt| &attempt.cause) {
+            Some(ConnectCause::Error(e)) => classify_sqlx(e, flavor),
+            _ => (ErrorKind::Timeout, true),
+        };
+    }
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx_err) => classify_sqlx(sqlx_err, flavor),
+        None => (ErrorKind::Internal, false),
+    }
+}
+
+fn classify_sqlx(sqlx_err: &sqlx::Error, flavor: Flavor) -> (ErrorKind, bool) {
+    let code = match sqlx_err {
+        sqlx::Error::Database(db_e) => db_e
+            .try_downcast_ref::<MySqlDatabaseError>()
+            .map(|e| e.number()),
+        _ => None,
+    };
+
+    let kind = match sqlx_err {
+        sqlx::Error::Database(_) => code.map_or(ErrorKind::Internal, |code| {
+            ErrorKind::from_code(code, flavor)
+        }),
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolClosed => ErrorKind::Connection,
+        sqlx::Error::PoolTimedOut => ErrorKind::Timeout,
+        sqlx::Error::Protocol(_) | sqlx::Error::Encode(_) => ErrorKind::Protocol,
+        sqlx::Error::Decode(_) | sqlx::Error::ColumnDecode { .. } => ErrorKind::Data,
+        _ => ErrorKind::Internal,
+    };
+
+    let retryable = match kind {
+        ErrorKind::Connection | ErrorKind::Deadlock => true,
+        // a lock wait timeout can go through once the other transaction is done, a statement
+        // that hit max_execution_time will just hit it again
+        ErrorKind::Timeout => code == Some(error_codes::ER_LOCK_WAIT_TIMEOUT),
+        _ => false,
+    };
+
+    (kind, retryable)
+}
+
+/// Pulls the key/constraint name out of messages like
+/// `Duplicate entry 'a' for key 'users.PRIMARY'`,
+/// `... CONSTRAINT `fk_owner` FOREIGN KEY ...` and `Check constraint 'positive' is violated.`
+fn constraint_name(code: u16, flavor: Flavor, message: &str) -> Option<&str> {
+    let between = |start: &str, end: char| {
+        let from = message.rfind(start)? + start.len();
+        let len = message[from..].find(end)?;
+        Some(&message[from..from + len])
+    };
+    match (flavor, code) {
+        (_, error_codes::ER_DUP_ENTRY | error_codes::ER_DUP_ENTRY_WITH_KEY_NAME) => {
+            between("for key '", '\'')
+        }
+        (_, error_codes::ER_ROW_IS_REFERENCED_2 | error_codes::ER_NO_REFERENCED_ROW_2) => {
+            between("CONSTRAINT `", '`')
+        }
+        (Flavor::MySql, error_codes::ER_CHECK_CONSTRAINT_VIOLATED)
+        | (Flavor::MariaDb, error_codes::ER_CONSTRAINT_FAILED) => between("constraint '", '\''),
+        _ => None,
+    }
+}
+
+fn write_mysql_error_fields(
+    state: &lua::State,
+    err: &MySqlDatabaseError,
+    flavor: Flavor,
+    out: &Table,
+) -> String {
     if let Some(sqlstate) = err.code() {
         out.raw_set(state, "sqlstate", sqlstate);
     }
     out.raw_set(state, "code", err.number());
+    if let Some(name) = constraint_name(err.number(), flavor, err.message()) {
+        out.raw_set(state, "constraint", name);
+    }
     err.message().to_string()
 }
 
-fn write_sqlx_error(state: &lua::State, err: &sqlx::Error, out: &Table) {
+fn write_sqlx_error(state: &lua::State, err: &sqlx::Error, flavor: Flavor, out: &Table) {
     let msg = match err {
         sqlx::Error::Database(db_e) => match db_e.try_downcast_ref::<MySqlDatabaseError>() {
-            Some(mysql_e) => write_mysql_error_fields(state, mysql_e, out),
+            Some(mysql_e) => write_mysql_error_fields(state, mysql_e, flavor, out),
             _ => err.to_string(),
         },
         _ => err.to_string(),
@@ -37,14 +411,43 @@ fn apply_error_metatable(state: &lua::State, tbl: &Table) {
 }
 
 pub fn to_error_table(state: &lua::State, err: &anyhow::Error) -> Table {
-    let out = state.create_table_with_capacity(0, 3);
+    server_error_table(state, err, Flavor::Unknown)
+}
+
+fn server_error_table(state: &lua::State, err: &anyhow::Error, flavor: Flavor) -> Table {
+    let out = state.create_table_with_capacity(0, 5);
 
     if let Some(sqlx_err) = err.downcast_ref::<sqlx::Error>() {
-        write_sqlx_error(state, sqlx_err, &out);
+        write_sqlx_error(state, sqlx_err, flavor, &out);
+    } else if let Some(connect_err) = err.downcast_ref::<ConnectError>() {
+        connect_err.write_fields(state, flavor, &out);
+    } else if let Some(warnings_err) = err.downcast_ref::<WarningsError>() {
+        warnings_err.write_fields(state, &out);
+        out.raw_set(state, "message", err.to_string());
     } else {
         out.raw_set(state, "message", err.to_string());
     }
 
+    let (kind, retryable) = classify(err, flavor);
+    out.raw_set(state, "kind", kind.as_str());
+    out.raw_set(state, "retryable", retryable);
+
     apply_error_metatable(state, &out);
     out
 }
+
+/// Same as `to_error_table`, with the query that failed and the server's id for the connection
+/// it ran on, the query has placeholders in place of the values
+pub fn to_query_error_table(
+    state: &lua::State,
+    err: &anyhow::Error,
+    query: &Query,
+    connection_id: Option<u64>,
+) -> Table {
+    let out = server_error_table(state, err, query.flavor);
+    out.raw_set(state, "query", &query.query);
+    if let Some(connection_id) = connection_id {
+        out.raw_set(state, "connection_id", connection_id);
+    }
+    out
+}
@@ -0,0 +1,116 @@
+use gmodx::lua::{self, Table};
+
+macro_rules! error_codes {
+    ($list:ident { $($name:ident = $code:literal,)* }) => {
+        // not all of them are used on the rust side, they're all exported to lua
+        $(#[allow(dead_code)] pub const $name: u16 = $code;)*
+
+        const $list: &[(&str, u16)] = &[$((stringify!($name), $code),)*];
+    };
+}
+
+// Server (ER_*) error codes both of them use, names are the ones MySQL/MariaDB use in their docs
+error_codes! {
+    SHARED {
+        ER_DUP_KEY = 1022,
+        ER_CON_COUNT_ERROR = 1040,
+        ER_DBACCESS_DENIED_ERROR = 1044,
+        ER_ACCESS_DENIED_ERROR = 1045,
+        ER_NO_DB_ERROR = 1046,
+        ER_BAD_NULL_ERROR = 1048,
+        ER_BAD_DB_ERROR = 1049,
+        ER_TABLE_EXISTS_ERROR = 1050,
+        ER_BAD_TABLE_ERROR = 1051,
+        ER_SERVER_SHUTDOWN = 1053,
+        ER_BAD_FIELD_ERROR = 1054,
+        ER_DUP_FIELDNAME = 1060,
+        ER_DUP_KEYNAME = 1061,
+        ER_DUP_ENTRY = 1062,
+        ER_PARSE_ERROR = 1064,
+        ER_EMPTY_QUERY = 1065,
+        ER_WRONG_VALUE_COUNT_ON_ROW = 1136,
+        ER_TABLEACCESS_DENIED_ERROR = 1142,
+        ER_COLUMNACCESS_DENIED_ERROR = 1143,
+        ER_NO_SUCH_TABLE = 1146,
+        ER_SYNTAX_ERROR = 1149,
+        ER_NET_PACKET_TOO_LARGE = 1153,
+        ER_LOCK_WAIT_TIMEOUT = 1205,
+        ER_LOCK_DEADLOCK = 1213,
+        ER_NO_REFERENCED_ROW = 1216,
+        ER_ROW_IS_REFERENCED = 1217,
+        ER_SPECIFIC_ACCESS_DENIED_ERROR = 1227,
+        ER_WARN_DATA_OUT_OF_RANGE = 1264,
+        WARN_DATA_TRUNCATED = 1265,
+        ER_TRUNCATED_WRONG_VALUE = 1292,
+        ER_QUERY_INTERRUPTED = 1317,
+        ER_NO_DEFAULT_FOR_FIELD = 1364,
+        ER_DIVISION_BY_ZERO = 1365,
+        ER_TRUNCATED_WRONG_VALUE_FOR_FIELD = 1366,
+        ER_ILLEGAL_VALUE_FOR_TYPE = 1367,
+        ER_PROCACCESS_DENIED_ERROR = 1370,
+        ER_DATA_TOO_LONG = 1406,
+        ER_ROW_IS_REFERENCED_2 = 1451,
+        ER_NO_REFERENCED_ROW_2 = 1452,
+        ER_DUP_ENTRY_WITH_KEY_NAME = 1586,
+        ER_DATA_OUT_OF_RANGE = 1690,
+    }
+}
+
+// Client (CR_*) error codes, from the client library so they're the same for both servers
+error_codes! {
+    CLIENT {
+        CR_CONNECTION_ERROR = 2002,
+        CR_CONN_HOST_ERROR = 2003,
+        CR_SERVER_GONE_ERROR = 2006,
+        CR_SERVER_LOST = 2013,
+        CR_SERVER_LOST_EXTENDED = 2055,
+    }
+}
+
+// Only MySQL has these, MariaDB either doesn't use the code or uses it for something else
+error_codes! {
+    MYSQL {
+        // max_execution_time
+        ER_QUERY_TIMEOUT = 3024,
+        ER_CHECK_CONSTRAINT_VIOLATED = 3819,
+        // 8.0.24+, wait_timeout ran out
+        ER_CLIENT_INTERACTION_TIMEOUT = 4031,
+    }
+}
+
+// Only MariaDB has these
+error_codes! {
+    MARIADB {
+        ER_CONNECTION_KILLED = 1927,
+        // max_statement_time
+        ER_STATEMENT_TIMEOUT = 1969,
+        ER_CONSTRAINT_FAILED = 4025,
+    }
+}
+
+/// Both ways, so `ERRORS.ER_DUP_ENTRY == 1062` and `ERRORS[1062] == "ER_DUP_ENTRY"`
+fn set_both_ways(state: &lua::State, table: &Table, codes: &[(&str, u16)]) {
+    for (name, code) in codes {
+        table.raw_set(state, *name, *code);
+        table.raw_set(state, *code, *name);
+    }
+}
+
+pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
+    let len = (SHARED.len() + CLIENT.len() + MYSQL.len() + MARIADB.len()) as i32;
+    let errors = state.create_table_with_capacity(0, len * 2 + 2);
+    set_both_ways(state, &errors, SHARED);
+    set_both_ways(state, &errors, CLIENT);
+
+    // the names don't clash so they're all there, the codes are only looked up per server
+    for (key, codes) in [("MYSQL", MYSQL), ("MARIADB", MARIADB)] {
+        let vendor = state.create_table_with_capacity(0, codes.len() as i32 * 2);
+        set_both_ways(state, &vendor, codes);
+        for (name, code) in codes {
+            errors.raw_set(state, *name, *code);
+        }
+        errors.raw_set(state, key, vendor);
+    }
+
+    goobie_mysql.raw_set(state, "ERRORS", errors);
+}
@@ -3,8 +3,11 @@ use gmodx::{gmod13_close, gmod13_open, lua, tokio_tasks};
 mod config;
 mod connection;
 mod error;
+mod error_codes;
 mod macros;
+mod promise;
 mod query;
+mod shutdown;
 mod state;
 
 pub use config::*;
@@ -17,7 +20,11 @@ fn gmod13_open(state: lua::State) {
     goobie_mysql.raw_set(&state, "MAJOR_VERSION", MAJOR_VERSION);
 
     connection::on_gmod_open(&state, &goobie_mysql);
+    query::on_gmod_open(&state, &goobie_mysql);
+    promise::on_gmod_open(&state, &goobie_mysql);
     crate::state::on_gmod_open(&state, &goobie_mysql);
+    error_codes::on_gmod_open(&state, &goobie_mysql);
+    shutdown::on_gmod_open(&state);
 
     state
         .set_global(GOOBIE_MYSQL_TABLE_NAME, goobie_mysql)
@@ -50,4 +57,6 @@ fn gmod13_open(state: lua::State) {
 }
 
 #[gmod13_close]
-fn gmod13_close(state: lua::State) {}
+fn gmod13_close(state: lua::State) {
+    shutdown::drain(&state);
+}
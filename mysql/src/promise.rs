@@ -0,0 +1,276 @@
+use anyhow::{Result, bail};
+use gmodx::lua::{
+    self, AnyUserData, Function, LuaResultExt as _, Table, UserData, UserDataRef, Value, ValueKind,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{Cancelled, to_error_table};
+
+#[derive(Clone)]
+enum Outcome {
+    /// `res, warnings`
+    Resolved(Option<Table>, Option<Table>),
+    Rejected(Table),
+}
+
+enum Handler {
+    Then(Function),
+    Catch(Function),
+    Finally(Function),
+    /// One of the promises given to `All`, `index` is where its result goes
+    All {
+        parent: UserDataRef<Promise>,
+        index: usize,
+    },
+}
+
+/// Filled for promises made by `All`
+struct AllState {
+    children: Vec<UserDataRef<Promise>>,
+    results: Table,
+    remaining: usize,
+}
+
+pub struct Promise {
+    outcome: Option<Outcome>,
+    handlers: Vec<Handler>,
+    /// Shared with the query, so cancelling one cancels the other
+    cancel: CancellationToken,
+    all: Option<AllState>,
+}
+
+impl Promise {
+    fn new() -> Self {
+        Self {
+            outcome: None,
+            handlers: Vec::new(),
+            cancel: CancellationToken::new(),
+            all: None,
+        }
+    }
+
+    fn create(state: &lua::State) -> UserDataRef<Promise> {
+        state.create_userdata(Self::new())
+    }
+
+    /// `promise` query option, `true` makes a new one, the lua side passes the one it already
+    /// returned when the query has to wait in the queue
+    pub fn from_opts(state: &lua::State, opts: Option<&Table>) -> Result<Option<PromiseHandle>> {
+        let Some(opts) = opts else {
+            return Ok(None);
+        };
+        let value = opts.get::<Value>(state, "promise")?;
+        let promise = match value.type_kind() {
+            ValueKind::Nil => return Ok(None),
+            ValueKind::Bool if value.clone().to::<bool>(state)? => Self::create(state),
+            ValueKind::Bool => return Ok(None),
+            ValueKind::UserData => match value.to::<AnyUserData>(state)?.cast_to(state) {
+                Some(promise) => promise,
+                None => bail!("promise must be true or a promise"),
+            },
+            _ => bail!("promise must be true or a promise"),
+        };
+        let cancel = promise.borrow().cancel.clone();
+        Ok(Some(PromiseHandle {
+            promise: promise.into_any(),
+            cancel,
+        }))
+    }
+
+    fn settle(state: &lua::State, this: &UserDataRef<Promise>, outcome: Outcome) {
+        let handlers = {
+            let mut promise = this.borrow_mut();
+            if promise.outcome.is_some() {
+                return;
+            }
+            promise.outcome = Some(outcome.clone());
+            // children of an `All` hold it in their handlers, don't keep them alive the other way too
+            promise.all = None;
+            std::mem::take(&mut promise.handlers)
+        };
+        for handler in handlers {
+            run_handler(state, handler, &outcome);
+        }
+    }
+
+    fn add_handler(state: &lua::State, this: &UserDataRef<Promise>, handler: Handler) {
+        let outcome = {
+            let mut promise = this.borrow_mut();
+            match &promise.outcome {
+                Some(outcome) => outcome.clone(),
+                None => {
+                    promise.handlers.push(handler);
+                    return;
+                }
+            }
+        };
+        run_handler(state, handler, &outcome);
+    }
+
+    fn cancel(state: &lua::State, this: &UserDataRef<Promise>) {
+        let children = {
+            let mut promise = this.borrow_mut();
+            if promise.outcome.is_some() {
+                return;
+            }
+            promise.cancel.cancel();
+            promise
+                .all
+                .as_mut()
+                .map(|all| std::mem::take(&mut all.children))
+                .unwrap_or_default()
+        };
+        let error_table = to_error_table(state, &Cancelled.into());
+        Self::settle(state, this, Outcome::Rejected(error_table));
+        for child in &children {
+            Self::cancel(state, child);
+        }
+    }
+
+    /// Resolves with a list of every promise's result once they all resolve, rejects with the
+    /// first error
+    fn all(state: &lua::State, promises: Vec<UserDataRef<Promise>>) -> UserDataRef<Promise> {
+        let parent = Self::create(state);
+        if promises.is_empty() {
+            Self::settle(
+                state,
+                &parent,
+                Outcome::Resolved(Some(state.create_table()), None),
+            );
+            return parent;
+        }
+
+        parent.borrow_mut().all = Some(AllState {
+            children: promises.clone(),
+            results: state.create_table_with_capacity(promises.len() as i32, 0),
+            remaining: promises.len(),
+        });
+        for (index, child) in promises.iter().enumerate() {
+            let parent = parent.clone();
+            Self::add_handler(state, child, Handler::All { parent, index });
+        }
+        parent
+    }
+}
+
+fn run_handler(state: &lua::State, handler: Handler, outcome: &Outcome) {
+    match (handler, outcome) {
+        (Handler::Then(func), Outcome::Resolved(res, warnings)) => {
+            func.call::<()>(state, (res.clone(), warnings.clone()))
+                .log();
+        }
+        (Handler::Catch(func), Outcome::Rejected(err)) => {
+            func.call::<()>(state, err).log();
+        }
+        (Handler::Finally(func), _) => {
+            func.call::<()>(state, ()).log();
+        }
+        (Handler::All { parent, index }, Outcome::Resolved(res, _)) => {
+            let done = {
+                let mut parent = parent.borrow_mut();
+                let Some(all) = parent.all.as_mut() else {
+                    return;
+                };
+                all.results.raw_set(state, index as i32 + 1, res.clone());
+                all.remaining -= 1;
+                (all.remaining == 0).then(|| all.results.clone())
+            };
+            if let Some(results) = done {
+                Promise::settle(state, &parent, Outcome::Resolved(Some(results), None));
+            }
+        }
+        (Handler::All { parent, .. }, Outcome::Rejected(err)) => {
+            Promise::settle(state, &parent, Outcome::Rejected(err.clone()));
+        }
+        _ => {}
+    }
+}
+
+/// What a query keeps of its promise, userdata refs can't leave the main thread
+pub struct PromiseHandle {
+    promise: AnyUserData,
+    cancel: CancellationToken,
+}
+
+impl PromiseHandle {
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn to_any(&self) -> AnyUserData {
+        self.promise.clone()
+    }
+
+    /// Takes the same `err, res, warnings` a callback gets
+    pub fn settle(
+        &self,
+        state: &lua::State,
+        (err, res, warnings): (Option<Table>, Option<Table>, Option<Table>),
+    ) {
+        let Some(promise) = self.promise.clone().cast_to::<Promise>(state) else {
+            return;
+        };
+        let outcome = match err {
+            Some(err) => Outcome::Rejected(err),
+            None => Outcome::Resolved(res, warnings),
+        };
+        Promise::settle(state, &promise, outcome);
+    }
+}
+
+impl UserData for Promise {
+    fn methods(methods: &mut lua::Methods) {
+        methods.add(
+            c"Then",
+            |state: &lua::State, this: UserDataRef<Promise>, func: Function| {
+                Promise::add_handler(state, &this, Handler::Then(func));
+                this
+            },
+        );
+
+        methods.add(
+            c"Catch",
+            |state: &lua::State, this: UserDataRef<Promise>, func: Function| {
+                Promise::add_handler(state, &this, Handler::Catch(func));
+                this
+            },
+        );
+
+        methods.add(
+            c"Finally",
+            |state: &lua::State, this: UserDataRef<Promise>, func: Function| {
+                Promise::add_handler(state, &this, Handler::Finally(func));
+                this
+            },
+        );
+
+        methods.add(
+            c"Cancel",
+            |state: &lua::State, this: UserDataRef<Promise>| {
+                Promise::cancel(state, &this);
+            },
+        );
+
+        methods.add(
+            c"IsPending",
+            |_: &lua::State, this: UserDataRef<Promise>| this.borrow().outcome.is_none(),
+        );
+    }
+}
+
+pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
+    goobie_mysql.raw_set(state, "PROMISE_META", Promise::init_methods_table(state));
+    goobie_mysql.raw_set(
+        state,
+        "NewPromise",
+        state.create_function(|state: &lua::State| Promise::create(state)),
+    );
+    goobie_mysql.raw_set(
+        state,
+        "All",
+        state.create_function(|state: &lua::State, promises: Vec<UserDataRef<Promise>>| {
+            Promise::all(state, promises)
+        }),
+    );
+}
@@ -0,0 +1,464 @@
+use std::{iter::Peekable, vec::IntoIter};
+
+use anyhow::{Result, anyhow, bail};
+use gmodx::{
+    bstr::ByteSlice as _,
+    lua::{self, Table},
+};
+use sqlx::mysql::MySqlConnection;
+
+use crate::connection::SessionInfo;
+
+use super::{
+    Param, QuerySettings,
+    escape::{quote_identifier, quote_qualified_identifier},
+    executor::execute_statement,
+    params::{is_null, parse_param},
+    result::ExecuteInfo,
+    warnings::{self, Warning, WarningsMode},
+};
+
+/// MySQL caps prepared statements at 65535 placeholders
+const MAX_PLACEHOLDERS: usize = u16::MAX as usize;
+
+/// Headroom left in each packet for protocol headers, statement id, null bitmap, etc.
+const PACKET_OVERHEAD: usize = 1024;
+
+/// Row alias used by `INSERT ... AS new ON DUPLICATE KEY UPDATE`
+const ROW_ALIAS: &str = "`new`";
+
+#[derive(Debug)]
+enum RowValue {
+    Bind(Param),
+    /// Written into the statement as is, used for NULL, DEFAULT and `no_escape_columns`
+    Literal(String),
+}
+
+type Row = Vec<RowValue>;
+
+#[derive(Debug, Clone, Copy)]
+enum Update {
+    /// `col = new.col`
+    Overwrite,
+    /// `col = col + new.col`
+    Increment,
+}
+
+#[derive(Debug)]
+enum OnDuplicate {
+    /// Used as is after `ON DUPLICATE KEY UPDATE`
+    Raw(String),
+    /// Quoted columns, rendered once we know which syntax the server supports
+    Columns(Vec<(String, Update)>),
+}
+
+/// A multi-row INSERT that gets split into as many statements as needed to keep
+/// each one under the server's `max_allowed_packet`
+#[derive(Debug)]
+pub struct BulkInsert {
+    /// `INSERT [IGNORE]|REPLACE INTO `table` (`a`,`b`) VALUES`
+    prefix: String,
+    on_duplicate: Option<OnDuplicate>,
+    rows: Vec<Row>,
+}
+
+impl BulkInsert {
+    pub fn new(
+        state: &lua::State,
+        table: lua::String,
+        columns: Table,
+        rows: Table,
+        opts: Option<&Table>,
+    ) -> Result<Self> {
+        let column_names = parse_columns(state, &columns)?;
+        let parsed_rows = parse_rows(state, &rows, &column_names)?;
+
+        let mut verb = "INSERT";
+        let mut on_duplicate = None;
+        if let Some(opts) = opts {
+            if opts.get::<Option<bool>>(state, "ignore")?.unwrap_or(false) {
+                verb = "INSERT IGNORE";
+            }
+            if let Some(value) = opts.get::<Option<lua::Value>>(state, "on_duplicate")? {
+                on_duplicate = Some(parse_on_duplicate(state, value)?);
+            }
+        }
+
+        Self::build(&table, &column_names, parsed_rows, verb, on_duplicate)
+    }
+
+    /// Builds the statement for `UpsertQuery`, `inserts` is either one keyed row or an array of them
+    pub fn upsert(state: &lua::State, table: lua::String, opts: &Table) -> Result<Self> {
+        // mysql doesn't use primary keys, but we require them to keep consistency with sqlite
+        if opts.get::<Option<Table>>(state, "primary_keys")?.is_none() {
+            bail!("upsert query must have primary_keys");
+        }
+
+        let inserts = opts
+            .get::<Option<Table>>(state, "inserts")?
+            .ok_or_else(|| anyhow!("upsert query must have inserts"))?;
+
+        let keyed_rows = if inserts.raw_len(state) > 0 {
+            let mut keyed_rows = Vec::new();
+            for (i, row) in inserts.ipairs::<lua::Value>(state) {
+                keyed_rows.push(
+                    row.to::<Table>(state)
+                        .map_err(|_| anyhow!("inserts row {i} must be a table"))?,
+                );
+            }
+            keyed_rows
+        } else {
+            vec![inserts]
+        };
+
+        // rows can have different keys, missing ones get the column's default like they would in
+        // a single row upsert
+        // sorted so the same upsert always generates the same statement, which keeps the statement cache useful
+        let mut column_names = Vec::new();
+        for row in &keyed_rows {
+            for (column, _) in row.pairs::<lua::String, lua::Value>(state) {
+                let column = to_utf8(&column, || "inserts column".to_string())?;
+                if !column_names.contains(&column) {
+                    column_names.push(column);
+                }
+            }
+        }
+        column_names.sort();
+        if column_names.is_empty() {
+            bail!("inserts cannot be empty");
+        }
+
+        let mut no_escape = Vec::new();
+        if let Some(columns) = opts.get::<Option<Table>>(state, "no_escape_columns")? {
+            for (_, column) in columns.ipairs::<lua::String>(state) {
+                no_escape.push(column.to_string());
+            }
+        }
+
+        let mut rows = Vec::with_capacity(keyed_rows.len());
+        for (i, row) in keyed_rows.iter().enumerate() {
+            rows.push(
+                parse_row(state, row, &column_names, &no_escape)
+                    .map_err(|e| anyhow!("inserts row {}: {e}", i + 1))?,
+            );
+        }
+
+        let mut updates = Vec::new();
+        for (key, update) in [
+            ("updates", Update::Overwrite),
+            ("increments", Update::Increment),
+        ] {
+            let Some(columns) = opts.get::<Option<Table>>(state, key)? else {
+                continue;
+            };
+            for (i, column) in columns.ipairs::<lua::String>(state) {
+                let column = to_utf8(&column, || format!("{key} column {i}"))?;
+                updates.push((quote_identifier(&column)?, update));
+            }
+        }
+
+        let on_duplicate = if updates.is_empty() {
+            // nothing to update, so we update the first column with itself
+            let column = quote_identifier(&column_names[0])?;
+            OnDuplicate::Raw(format!("{column}={column}"))
+        } else {
+            OnDuplicate::Columns(updates)
+        };
+
+        Self::build(&table, &column_names, rows, "INSERT", Some(on_duplicate))
+    }
+
+    fn build(
+        table: &lua::String,
+        columns: &[String],
+        rows: Vec<Row>,
+        verb: &str,
+        on_duplicate: Option<OnDuplicate>,
+    ) -> Result<Self> {
+        let table = quote_qualified_identifier(&to_utf8(table, || "table name".to_string())?)?;
+
+        if columns.is_empty() {
+            bail!("columns cannot be empty");
+        }
+        let quoted_columns = columns
+            .iter()
+            .map(|c| quote_identifier(c))
+            .collect::<Result<Vec<_>>>()?;
+
+        let prefix = format!("{verb} INTO {table} ({}) VALUES", quoted_columns.join(","));
+
+        Ok(Self {
+            prefix,
+            on_duplicate,
+            rows,
+        })
+    }
+
+    pub async fn execute(
+        &mut self,
+        conn: &mut MySqlConnection,
+        session: &SessionInfo,
+        settings: &QuerySettings,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<ExecuteInfo> {
+        let budget = session.max_allowed_packet.saturating_sub(PACKET_OVERHEAD);
+        let suffix = self.suffix(session);
+
+        let mut info = ExecuteInfo::default();
+        let mut rows = std::mem::take(&mut self.rows).into_iter().peekable();
+
+        while rows.peek().is_some() {
+            let (sql, params) = self.next_statement(&mut rows, budget, &suffix);
+
+            let mut query = sqlx::query(&sql);
+            for param in params {
+                query = param.bind(query);
+            }
+            info.merge(execute_statement(query, conn, session, settings).await?);
+            // each chunk is its own statement, so its warnings have to be read before the next one
+            if settings.warnings != WarningsMode::Ignore {
+                warnings.extend(warnings::fetch(conn).await?);
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Renders all rows into one statement without running it, used by `return_query`
+    pub fn into_statement(mut self, session: &SessionInfo) -> Result<(String, Vec<Param>)> {
+        let suffix = self.suffix(session);
+        let mut rows = std::mem::take(&mut self.rows).into_iter().peekable();
+        let statement = self.next_statement(&mut rows, usize::MAX, &suffix);
+        if rows.peek().is_some() {
+            bail!("too many values to fit in a single statement");
+        }
+        Ok(statement)
+    }
+
+    /// Takes as many rows as fit in `budget` bytes, always at least one
+    fn next_statement(
+        &self,
+        rows: &mut Peekable<IntoIter<Row>>,
+        budget: usize,
+        suffix: &str,
+    ) -> (String, Vec<Param>) {
+        let mut sql = self.prefix.clone();
+        let mut params = Vec::new();
+        let mut size = self.prefix.len() + suffix.len();
+        let mut count = 0;
+
+        while let Some(row) = rows.peek() {
+            // "(?,?,?)," in the statement text plus the bound values
+            let row_size = row.len() * 2
+                + 2
+                + row
+                    .iter()
+                    .map(|v| match v {
+                        RowValue::Bind(param) => param.encoded_size(),
+                        RowValue::Literal(s) => s.len(),
+                    })
+                    .sum::<usize>();
+            let row_placeholders = row
+                .iter()
+                .filter(|v| matches!(v, RowValue::Bind(_)))
+                .count();
+
+            // if a single row is too big the server will tell
+            let is_first = count == 0;
+            if !is_first
+                && (size.saturating_add(row_size) > budget
+                    || params.len() + row_placeholders > MAX_PLACEHOLDERS)
+            {
+                break;
+            }
+
+            let row = rows.next().expect("row was peeked");
+            if !is_first {
+                sql.push(',');
+            }
+            sql.push('(');
+            for (idx, value) in row.into_iter().enumerate() {
+                if idx > 0 {
+                    sql.push(',');
+                }
+                match value {
+                    RowValue::Bind(param) => {
+                        sql.push('?');
+                        params.push(param);
+                    }
+                    RowValue::Literal(s) => sql.push_str(&s),
+                }
+            }
+            sql.push(')');
+            size += row_size;
+            count += 1;
+        }
+
+        sql.push_str(suffix);
+        (sql, params)
+    }
+
+    fn suffix(&self, session: &SessionInfo) -> String {
+        let columns = match &self.on_duplicate {
+            None => return String::new(),
+            Some(OnDuplicate::Raw(assignments)) => {
+                return format!(" ON DUPLICATE KEY UPDATE {assignments}");
+            }
+            Some(OnDuplicate::Columns(columns)) => columns,
+        };
+
+        // VALUES(col) is deprecated since MySQL 8.0.20, but MariaDB and older MySQL only have that
+        let use_alias = session.supports_insert_alias();
+        let assignments = columns
+            .iter()
+            .map(|(column, update)| {
+                let new_value = if use_alias {
+                    format!("{ROW_ALIAS}.{column}")
+                } else {
+                    format!("VALUES({column})")
+                };
+                match update {
+                    Update::Overwrite => format!("{column}={new_value}"),
+                    Update::Increment => format!("{column}={column}+{new_value}"),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        if use_alias {
+            format!(" AS {ROW_ALIAS} ON DUPLICATE KEY UPDATE {assignments}")
+        } else {
+            format!(" ON DUPLICATE KEY UPDATE {assignments}")
+        }
+    }
+}
+
+impl std::fmt::Display for BulkInsert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ... ({} rows)", self.prefix, self.rows.len())?;
+        match &self.on_duplicate {
+            None => Ok(()),
+            Some(OnDuplicate::Raw(assignments)) => {
+                write!(f, " ON DUPLICATE KEY UPDATE {assignments}")
+            }
+            Some(OnDuplicate::Columns(columns)) => {
+                let columns = columns
+                    .iter()
+                    .map(|(column, _)| column.as_str())
+                    .collect::<Vec<_>>();
+                write!(f, " ON DUPLICATE KEY UPDATE {}", columns.join(","))
+            }
+        }
+    }
+}
+
+fn to_utf8(s: &lua::String, what: impl FnOnce() -> String) -> Result<String> {
+    s.to_str()
+        .map(str::to_string)
+        .map_err(|_| anyhow!("{} must be valid UTF-8", what()))
+}
+
+fn parse_columns(state: &lua::State, columns: &Table) -> Result<Vec<String>> {
+    let mut column_names = Vec::new();
+    for (i, column) in columns.ipairs::<lua::String>(state) {
+        column_names.push(to_utf8(&column, || format!("column {i}"))?);
+    }
+    Ok(column_names)
+}
+
+fn parse_rows(state: &lua::State, rows: &Table, columns: &[String]) -> Result<Vec<Row>> {
+    let mut parsed_rows = Vec::new();
+    for (i, row) in rows.ipairs::<lua::Value>(state) {
+        let row = row
+            .to::<Table>(state)
+            .map_err(|_| anyhow!("row {i} must be a table"))?;
+        parsed_rows
+            .push(parse_row(state, &row, columns, &[]).map_err(|e| anyhow!("row {i}: {e}"))?);
+    }
+    Ok(parsed_rows)
+}
+
+fn parse_row(
+    state: &lua::State,
+    row: &Table,
+    columns: &[String],
+    no_escape: &[String],
+) -> Result<Row> {
+    let positional = row.raw_len(state) > 0;
+    // a short row is more likely a mistake than missing values, those have to be NULL
+    if positional && row.raw_len(state) != columns.len() {
+        bail!(
+            "has {} values but there are {} columns, use NULL for empty values",
+            row.raw_len(state),
+            columns.len()
+        );
+    }
+
+    let mut values = Vec::with_capacity(columns.len());
+    for (idx, column) in columns.iter().enumerate() {
+        let value = if positional {
+            row.raw_get::<lua::Value>(state, idx as i32 + 1)?
+        } else {
+            row.raw_get::<lua::Value>(state, column.as_str())?
+        };
+
+        // a keyed row without the column, leaving it out of a single row insert would give it its
+        // default too
+        if !positional && value.type_kind() == lua::ValueKind::Nil {
+            values.push(RowValue::Literal("DEFAULT".to_string()));
+            continue;
+        }
+        if value.type_kind() == lua::ValueKind::Nil || is_null(state, &value) {
+            values.push(RowValue::Literal("NULL".to_string()));
+            continue;
+        }
+
+        let param = parse_param(state, value).map_err(|e| anyhow!("column '{column}': {e}"))?;
+        if no_escape.contains(column) {
+            values.push(RowValue::Literal(no_escape_literal(param)));
+        } else {
+            values.push(RowValue::Bind(param));
+        }
+    }
+
+    Ok(values)
+}
+
+/// Same as `HandleNoEscape` in common.lua, the value is trusted to be safe
+fn no_escape_literal(param: Param) -> String {
+    match param {
+        Param::Number(n) => n.to_string(),
+        Param::Bool(b) => if b { "TRUE" } else { "FALSE" }.to_string(),
+        Param::String(s) => format!("'{s}'"),
+        Param::Text(s) => format!("'{s}'"),
+        Param::Decimal(d) => d.to_string(),
+        Param::Null => "NULL".to_string(),
+    }
+}
+
+/// `on_duplicate` is either a list of columns to overwrite with the inserted values,
+/// or a string that gets used as is after `ON DUPLICATE KEY UPDATE`
+fn parse_on_duplicate(state: &lua::State, on_duplicate: lua::Value) -> Result<OnDuplicate> {
+    use lua::ValueKind;
+    match on_duplicate.type_kind() {
+        ValueKind::String => Ok(OnDuplicate::Raw(
+            on_duplicate.to::<lua::String>(state)?.to_string(),
+        )),
+        ValueKind::Table => {
+            let columns = on_duplicate.to::<Table>(state)?;
+            let mut updates = Vec::new();
+            for (i, column) in columns.ipairs::<lua::String>(state) {
+                let column = to_utf8(&column, || format!("on_duplicate column {i}"))?;
+                updates.push((quote_identifier(&column)?, Update::Overwrite));
+            }
+            if updates.is_empty() {
+                bail!("on_duplicate cannot be empty");
+            }
+            Ok(OnDuplicate::Columns(updates))
+        }
+        _ => bail!(
+            "on_duplicate must be a table or a string, got {}",
+            on_duplicate.type_name()
+        ),
+    }
+}
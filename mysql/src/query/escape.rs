@@ -0,0 +1,159 @@
+use anyhow::{Result, bail};
+use gmodx::{
+    bstr::ByteSlice as _,
+    lua::{self, Table},
+};
+
+use crate::connection::SessionInfo;
+
+use super::{
+    Param,
+    lexer::{self, Piece},
+    params::parse_param,
+};
+
+/// MySQL limits identifiers (tables, columns, ...) to 64 characters
+const MAX_IDENTIFIER_LEN: usize = 64;
+
+/// Quotes a single identifier with backticks, e.g. `users` -> `` `users` ``
+pub fn quote_identifier(name: &str) -> Result<String> {
+    if name.is_empty() {
+        bail!("identifier cannot be empty");
+    }
+    if name.chars().count() > MAX_IDENTIFIER_LEN {
+        bail!("identifier '{name}' is longer than {MAX_IDENTIFIER_LEN} characters");
+    }
+    if name.contains('\0') {
+        bail!("identifier '{name}' cannot contain NUL characters");
+    }
+    if name.ends_with(' ') {
+        bail!("identifier '{name}' cannot end with a space");
+    }
+
+    Ok(format!("`{}`", name.replace('`', "``")))
+}
+
+/// Quotes a possibly schema qualified name, e.g. `db.users` -> `` `db`.`users` ``
+pub fn quote_qualified_identifier(name: &str) -> Result<String> {
+    let parts = name
+        .split('.')
+        .map(quote_identifier)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("."))
+}
+
+/// Charsets where the second byte of a multibyte character can be 0x5c (`\`), a `\` the server
+/// reads as an escape could be part of a character
+const UNSAFE_CHARSETS: &[&str] = &["big5", "cp932", "gb18030", "gbk", "sjis"];
+
+/// Quotes a string literal so it reads back the same whether `NO_BACKSLASH_ESCAPES` is set or not,
+/// `sql_mode` can be changed at any time with `SET` so it can't be relied on:
+/// - doubled quotes if there's no `\` or NUL in it, those mean the same in both modes
+/// - a `_utf8mb4` hex literal if there is, or the client charset is unsafe
+/// - a plain hex literal if the value isn't UTF-8
+pub fn quote_string(value: &[u8], session: &SessionInfo) -> String {
+    let value = match std::str::from_utf8(value) {
+        Ok(value) => value,
+        Err(_) => return quote_hex(value),
+    };
+    if value.contains(['\\', '\0']) || UNSAFE_CHARSETS.contains(&session.charset.as_str()) {
+        // the introducer keeps it a text string instead of a binary one
+        return format!("_utf8mb4 {}", quote_hex(value.as_bytes()));
+    }
+
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('\'');
+    out.push_str(&value.replace('\'', "''"));
+    out.push('\'');
+    out
+}
+
+fn quote_hex(value: &[u8]) -> String {
+    if value.is_empty() {
+        // X'' is valid but it's a binary string, this keeps it a normal empty string
+        return "''".to_string();
+    }
+    let mut out = String::with_capacity(value.len() * 2 + 3);
+    out.push_str("X'");
+    for b in value {
+        out.push_str(&format!("{b:02X}"));
+    }
+    out.push('\'');
+    out
+}
+
+/// Turns a parameter into a literal that can be put in a query as is
+pub fn quote_param(param: &Param, session: &SessionInfo) -> Result<String> {
+    let literal = match param {
+        Param::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        Param::Number(n) if !n.is_finite() => bail!("{n} can't be used in a query"),
+        Param::Number(n) => n.to_string(),
+        Param::String(s) => quote_string(s, session),
+        Param::Text(s) => quote_string(s.as_bytes(), session),
+        // an unquoted literal with a decimal point is an exact DECIMAL in MySQL
+        Param::Decimal(d) => d.to_string(),
+        Param::Null => "NULL".to_string(),
+    };
+    Ok(literal)
+}
+
+/// Replaces `?` placeholders with escaped literals, used for raw queries which can't be prepared
+pub fn interpolate(sql: &str, params: Vec<Param>, session: &SessionInfo) -> Result<String> {
+    let pieces = lexer::split(sql, !session.no_backslash_escapes);
+    let placeholders = pieces
+        .iter()
+        .filter(|p| matches!(p, Piece::Positional))
+        .count();
+    if placeholders != params.len() {
+        bail!(
+            "query has {placeholders} placeholders but {} parameters were passed",
+            params.len()
+        );
+    }
+
+    let mut params = params.iter();
+    let mut out = String::with_capacity(sql.len());
+    for piece in pieces {
+        match piece {
+            Piece::Positional => {
+                let param = params.next().expect("placeholders were counted");
+                out.push_str(&quote_param(param, session)?);
+            }
+            piece => piece.push_to(&mut out),
+        }
+    }
+    Ok(out)
+}
+
+/// Escapes a lua value into a literal, `NULL` is kept as is
+pub fn escape_value(
+    state: &lua::State,
+    value: lua::Value,
+    session: &SessionInfo,
+) -> Result<String> {
+    quote_param(&parse_param(state, value)?, session)
+}
+
+pub fn escape_identifier(name: &lua::String) -> Result<String> {
+    match name.to_str() {
+        Ok(name) => quote_identifier(name),
+        Err(_) => bail!("identifier must be valid UTF-8"),
+    }
+}
+
+pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
+    // without a connection we can only assume the client charset is utf8mb4, the literals don't
+    // depend on sql_mode
+    goobie_mysql.raw_set(
+        state,
+        "Escape",
+        state.create_function(|state: &lua::State, value: lua::Value| {
+            escape_value(state, value, &SessionInfo::default())
+        }),
+    );
+    goobie_mysql.raw_set(
+        state,
+        "EscapeIdentifier",
+        state.create_function(|_: &lua::State, name: lua::String| escape_identifier(&name)),
+    );
+}
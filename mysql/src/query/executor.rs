@@ -1,16 +1,22 @@
 use anyhow::Result;
-use sqlx::{Executor, MySqlConnection, mysql::MySqlConnection as Conn};
+use futures_util::TryStreamExt as _;
+use sqlx::{Either, Executor, MySqlConnection, Row as _, mysql::MySqlConnection as Conn};
+
+use crate::connection::SessionInfo;
 
 use super::{
-    Param, Query, QueryResult,
-    result::{convert_row, convert_rows},
-    types::QueryType,
+    Query, QueryResult, escape,
+    result::{ExecuteInfo, convert_row, convert_rows},
+    types::{QuerySettings, QueryType},
+    warnings::{self, WarningsError, WarningsMode},
 };
 
 pub async fn execute_query<'a, 'q, E>(
     query: E,
     conn: &'q mut Conn,
     query_type: &QueryType,
+    session: &SessionInfo,
+    settings: &QuerySettings,
 ) -> Result<QueryResult>
 where
     E: 'q + sqlx::Execute<'q, sqlx::MySql>,
@@ -21,41 +27,138 @@ where
             Ok(QueryResult::Run)
         }
         QueryType::Execute => {
-            let info = conn.execute(query).await?;
+            let info = execute_statement(query, conn, session, settings).await?;
             Ok(QueryResult::Execute(info))
         }
         QueryType::FetchAll => {
             let rows = conn.fetch_all(query).await?;
-            let rows = convert_rows(&rows);
+            let rows = convert_rows(&rows, settings.decimal);
             Ok(QueryResult::Rows(rows))
         }
         QueryType::FetchOne => {
             let row = conn.fetch_optional(query).await?;
-            let row = convert_row(&row);
+            let row = convert_row(&row, settings.decimal);
             Ok(QueryResult::Row(row))
         }
+        QueryType::ExecuteReturning => {
+            let mut info = ExecuteInfo::default();
+            let mut rows = Vec::new();
+            let mut stream = conn.fetch_many(query);
+            while let Some(step) = stream.try_next().await? {
+                match step {
+                    Either::Left(res) => info.merge(res.into()),
+                    Either::Right(row) => rows.push(row),
+                }
+            }
+            drop(stream);
+            extend_info(conn, session, settings, &mut info).await?;
+            Ok(QueryResult::Returning(
+                info,
+                convert_rows(&rows, settings.decimal),
+            ))
+        }
+    }
+}
+
+/// Runs a statement that doesn't return rows, with `extended_info` it also asks the server
+/// for the warning count, which costs an extra round trip
+pub async fn execute_statement<'q, E>(
+    query: E,
+    conn: &mut Conn,
+    session: &SessionInfo,
+    settings: &QuerySettings,
+) -> Result<ExecuteInfo>
+where
+    E: 'q + sqlx::Execute<'q, sqlx::MySql>,
+{
+    let mut info = ExecuteInfo::from(conn.execute(query).await?);
+    extend_info(conn, session, settings, &mut info).await?;
+    Ok(info)
+}
+
+async fn extend_info(
+    conn: &mut Conn,
+    session: &SessionInfo,
+    settings: &QuerySettings,
+    info: &mut ExecuteInfo,
+) -> Result<()> {
+    if settings.extended_info {
+        // SHOW is a diagnostic statement, so it doesn't clear the warnings it's counting
+        let row = conn.fetch_one("SHOW COUNT(*) WARNINGS").await?;
+        let count = row
+            .try_get::<u64, _>(0)
+            .or_else(|_| row.try_get::<i64, _>(0).map(|c| c as u64))?;
+        info.warning_count = Some(count);
+        info.set_insert_ids(session.auto_increment_increment);
     }
+
+    Ok(())
 }
 
 impl Query {
-    pub async fn start(&mut self, conn: &mut MySqlConnection) {
+    pub async fn start(&mut self, conn: &mut MySqlConnection, session: &SessionInfo) {
+        self.run(conn, session).await;
+
+        if self.result.is_err() || self.settings.warnings == WarningsMode::Ignore {
+            return;
+        }
+
+        // bulk inserts already read them after each chunk
+        if self.bulk.is_none() {
+            match warnings::fetch(conn).await {
+                Ok(warnings) => self.warnings = warnings,
+                Err(e) => {
+                    self.result = Err(e);
+                    return;
+                }
+            }
+        }
+
+        // notes alone don't fail it, idempotent DDL like `DROP TABLE IF EXISTS` leaves them
+        if self.settings.warnings == WarningsMode::Error
+            && self.warnings.iter().any(|warning| !warning.is_note())
+        {
+            self.result = Err(WarningsError(std::mem::take(&mut self.warnings)).into());
+        }
+    }
+
+    async fn run(&mut self, conn: &mut MySqlConnection, session: &SessionInfo) {
         let qtype = &self.qtype;
 
-        if self.raw {
+        if let Some(bulk) = self.bulk.as_mut() {
+            self.result = bulk
+                .execute(conn, session, &self.settings, &mut self.warnings)
+                .await
+                .map(QueryResult::Execute);
+        } else if self.raw {
+            // raw queries can't be prepared, so params are escaped into the query text instead
+            // self.query is left as is so errors don't show the values
+            let interpolated;
+            let query = if self.params.is_empty() {
+                self.query.as_str()
+            } else {
+                let params = std::mem::take(&mut self.params);
+                match escape::interpolate(&self.query, params, session) {
+                    Ok(query) => {
+                        interpolated = query;
+                        interpolated.as_str()
+                    }
+                    Err(e) => {
+                        self.result = Err(e);
+                        return;
+                    }
+                }
+            };
             // &str gets treated as raw query in sqlx
-            self.result = execute_query(self.query.as_str(), conn, qtype).await;
+            self.result = execute_query(query, conn, qtype, session, &self.settings).await;
         } else {
             let mut query = sqlx::query(self.query.as_str());
 
             for param in self.params.drain(..) {
-                query = match param {
-                    Param::Bool(b) => query.bind(b),
-                    Param::Number(n) => query.bind(n),
-                    Param::String(s) => query.bind::<Vec<u8>>(s.into()),
-                };
+                query = param.bind(query);
             }
 
-            self.result = execute_query(query, conn, qtype).await;
+            self.result = execute_query(query, conn, qtype, session, &self.settings).await;
         }
     }
 }
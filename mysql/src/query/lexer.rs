@@ -0,0 +1,213 @@
+/// A piece of a query, placeholders inside strings, quoted identifiers and comments are left as sql
+#[derive(Debug, PartialEq)]
+pub enum Piece<'a> {
+    Sql(&'a str),
+    /// `?`
+    Positional,
+    /// `:name`, without the colon
+    Named(&'a str),
+}
+
+impl Piece<'_> {
+    pub fn push_to(&self, out: &mut String) {
+        match self {
+            Piece::Sql(s) => out.push_str(s),
+            Piece::Positional => out.push('?'),
+            Piece::Named(name) => {
+                out.push(':');
+                out.push_str(name);
+            }
+        }
+    }
+}
+
+/// Splits a query on its `?` and `:name` placeholders, `backslash_escapes` should be false when the
+/// server has `NO_BACKSLASH_ESCAPES` set, as `\` doesn't escape quotes in that mode
+pub fn split(sql: &str, backslash_escapes: bool) -> Vec<Piece<'_>> {
+    let bytes = sql.as_bytes();
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"' | b'`') => {
+                i = skip_quoted(bytes, i + 1, quote, backslash_escapes && quote != b'`');
+            }
+            b'#' => i = skip_line(bytes, i),
+            // "--" only starts a comment when followed by whitespace or a control character
+            b'-' if bytes.get(i + 1) == Some(&b'-')
+                && bytes.get(i + 2).is_none_or(|b| *b <= b' ') =>
+            {
+                i = skip_line(bytes, i)
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = match sql[i + 2..].find("*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => bytes.len(),
+                };
+            }
+            b'?' => {
+                if start < i {
+                    pieces.push(Piece::Sql(&sql[start..i]));
+                }
+                pieces.push(Piece::Positional);
+                i += 1;
+                start = i;
+            }
+            // `:=` is assignment and `a:b` isn't a placeholder, a name has to follow the colon directly
+            b':' if bytes.get(i + 1).is_some_and(|b| is_name_start(*b))
+                && (i == 0 || !is_name_char(bytes[i - 1])) =>
+            {
+                let name_start = i + 1;
+                let mut end = name_start;
+                while end < bytes.len() && is_name_char(bytes[end]) {
+                    end += 1;
+                }
+                if start < i {
+                    pieces.push(Piece::Sql(&sql[start..i]));
+                }
+                pieces.push(Piece::Named(&sql[name_start..end]));
+                i = end;
+                start = i;
+            }
+            _ => i += 1,
+        }
+    }
+
+    if start < bytes.len() {
+        pieces.push(Piece::Sql(&sql[start..]));
+    }
+
+    pieces
+}
+
+/// The query with its values replaced by `?` and comments and extra whitespace dropped, so the
+/// same query with different values gives the same fingerprint. Lists like `IN (1, 2, 3)` and
+/// multi-row `VALUES` fold into a single `(?)`
+pub fn fingerprint(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut space = false;
+    let mut i = 0;
+
+    let push = |out: &mut Vec<u8>, space: &mut bool, piece: &[u8]| {
+        if std::mem::take(space) && !out.is_empty() && piece != b"," && piece != b")" {
+            out.push(b' ');
+        }
+        out.extend_from_slice(piece);
+    };
+
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'\'' | b'"') => {
+                i = skip_quoted(bytes, i + 1, quote, true);
+                push(&mut out, &mut space, b"?");
+            }
+            b'`' => {
+                let start = i;
+                i = skip_quoted(bytes, i + 1, b'`', false);
+                push(&mut out, &mut space, &bytes[start..i]);
+            }
+            b'#' => {
+                i = skip_line(bytes, i);
+                space = true;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-')
+                && bytes.get(i + 2).is_none_or(|b| *b <= b' ') =>
+            {
+                i = skip_line(bytes, i);
+                space = true;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = match sql[i + 2..].find("*/") {
+                    Some(end) => i + 2 + end + 2,
+                    None => bytes.len(),
+                };
+                space = true;
+            }
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                space = true;
+            }
+            b'?' => {
+                i += 1;
+                push(&mut out, &mut space, b"?");
+            }
+            b':' if bytes.get(i + 1).is_some_and(|b| is_name_start(*b))
+                && (i == 0 || !is_name_char(bytes[i - 1])) =>
+            {
+                i += 1;
+                while i < bytes.len() && is_name_char(bytes[i]) {
+                    i += 1;
+                }
+                push(&mut out, &mut space, b"?");
+            }
+            // a number on its own, not the end of a name like `t1`
+            b if b.is_ascii_digit() && (i == 0 || !is_name_char(bytes[i - 1])) => {
+                while i < bytes.len() && (is_name_char(bytes[i]) || bytes[i] == b'.') {
+                    i += 1;
+                }
+                push(&mut out, &mut space, b"?");
+            }
+            b if is_name_char(b) => {
+                let start = i;
+                while i < bytes.len() && is_name_char(bytes[i]) {
+                    i += 1;
+                }
+                push(&mut out, &mut space, &bytes[start..i]);
+            }
+            _ => {
+                i += 1;
+                push(&mut out, &mut space, &bytes[i - 1..i]);
+            }
+        }
+        // lists always come out as `(a, b)`, however they were written
+        match out.last() {
+            Some(b'(') => space = false,
+            Some(b',') => space = true,
+            _ => {}
+        }
+    }
+
+    let mut fingerprint = String::from_utf8_lossy(&out).into_owned();
+    for (list, folded) in [("?, ?", "?"), ("(?), (?)", "(?)")] {
+        while fingerprint.contains(list) {
+            fingerprint = fingerprint.replace(list, folded);
+        }
+    }
+    fingerprint
+}
+
+/// Returns the index after the closing quote, a doubled quote is an escaped one
+fn skip_quoted(bytes: &[u8], mut i: usize, quote: u8, backslash_escapes: bool) -> usize {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if backslash_escapes => i += 2,
+            b if b == quote => {
+                if bytes.get(i + 1) == Some(&quote) {
+                    i += 2;
+                } else {
+                    return i + 1;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+fn skip_line(bytes: &[u8], i: usize) -> usize {
+    match bytes[i..].iter().position(|b| *b == b'\n') {
+        Some(end) => i + end + 1,
+        None => bytes.len(),
+    }
+}
+
+fn is_name_start(b: u8) -> bool {
+    b.is_ascii_alphabetic() || b == b'_'
+}
+
+fn is_name_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
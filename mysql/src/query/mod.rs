@@ -1,8 +1,27 @@
+mod bulk;
+mod escape;
 mod executor;
+mod lexer;
 mod params;
 mod result;
 mod types;
+mod warnings;
 
-pub use params::{Param, parse_params};
-pub use result::QueryResult;
-pub use types::{Query, QueryType};
+pub use bulk::BulkInsert;
+pub use escape::{escape_identifier, escape_value};
+use gmodx::lua::{self, Table};
+pub use lexer::fingerprint;
+pub use params::{Param, bind_params};
+pub use result::{ColumnValue, QueryResult};
+pub use types::{Priority, Query, QuerySettings, QueryType, next_query_id};
+pub use warnings::{WarningsError, WarningsMode, warnings_table};
+
+pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
+    escape::on_gmod_open(state, goobie_mysql);
+    params::on_gmod_open(state, goobie_mysql);
+    goobie_mysql.raw_set(
+        state,
+        "NewQueryID",
+        state.create_function(|_: &lua::State| next_query_id()),
+    );
+}
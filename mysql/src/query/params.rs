@@ -1,26 +1,338 @@
-use anyhow::{Result, bail};
-use gmodx::lua::{self, Table};
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use gmodx::{
+    bstr::ByteSlice as _,
+    lua::{self, AnyUserData, Table, ToLua, UserData, UserDataRef},
+};
+use sqlx::{MySql, mysql::MySqlArguments, types::Decimal};
+
+use crate::connection::SessionInfo;
+
+use super::lexer::{self, Piece};
+
+pub type MySqlQuery<'q> = sqlx::query::Query<'q, MySql, MySqlArguments>;
 
 #[derive(Debug, Clone)]
 pub enum Param {
     Number(f64),
+    /// Plain lua strings, bound as binary as they can hold any bytes
     String(lua::String),
+    /// `goobie_mysql.Text(s)`, bound as a UTF-8 string so the column's collation applies
+    Text(String),
     Bool(bool),
+    /// `goobie_mysql.Decimal(s)`, bound as an exact DECIMAL instead of a double
+    Decimal(Decimal),
+    Null,
+}
+
+impl Param {
+    pub fn bind(self, query: MySqlQuery<'_>) -> MySqlQuery<'_> {
+        match self {
+            Param::Bool(b) => query.bind(b),
+            Param::Number(n) => query.bind(n),
+            Param::String(s) => query.bind::<Vec<u8>>(s.into()),
+            Param::Text(s) => query.bind(s),
+            Param::Decimal(d) => query.bind(d),
+            Param::Null => query.bind(None::<String>),
+        }
+    }
+
+    /// Rough size of the value once it's encoded in a COM_STMT_EXECUTE packet
+    pub fn encoded_size(&self) -> usize {
+        // 2 bytes for the type of each parameter
+        2 + match self {
+            Param::Bool(_) => 1,
+            Param::Number(_) => 8,
+            // up to 9 bytes for the length prefix
+            Param::String(s) => s.len() + 9,
+            Param::Text(s) => s.len() + 9,
+            // sent as a length prefixed string
+            Param::Decimal(_) => 32,
+            // only takes a bit in the null bitmap
+            Param::Null => 0,
+        }
+    }
+}
+
+impl ToLua for Param {
+    fn push_to_stack(self, state: &lua::State) {
+        match self {
+            Param::Number(n) => n.push_to_stack(state),
+            Param::String(s) => s.push_to_stack(state),
+            Param::Text(s) => s.push_to_stack(state),
+            Param::Decimal(d) => d.to_string().push_to_stack(state),
+            Param::Bool(b) => b.push_to_stack(state),
+            Param::Null => state
+                .get_global::<lua::Value>("NULL")
+                .unwrap_or(lua::Nil.to_value(state))
+                .push_to_stack(state),
+        }
+    }
 }
 
-pub fn parse_params(state: &lua::State, params: Table) -> Result<Vec<Param>> {
+/// A parameter wrapped by `goobie_mysql.Text(s)`, `Blob(s)` or `Decimal(s)` to pick how it's bound
+#[derive(Debug)]
+pub struct TypedParam(Param);
+
+impl UserData for TypedParam {
+    fn meta_methods(methods: &mut lua::Methods) {
+        methods.add(
+            c"__tostring",
+            |_: &lua::State, p: UserDataRef<TypedParam>| match &p.borrow().0 {
+                Param::Text(s) => format!("Text({s})"),
+                Param::String(s) => format!("Blob({} bytes)", s.len()),
+                Param::Decimal(d) => format!("Decimal({d})"),
+                param => format!("{param:?}"),
+            },
+        );
+    }
+}
+
+pub fn on_gmod_open(state: &lua::State, goobie_mysql: &Table) {
+    goobie_mysql.raw_set(
+        state,
+        "Text",
+        state.create_function(
+            |state: &lua::State, s: lua::String| -> Result<UserDataRef<TypedParam>> {
+                let Ok(s) = s.to_str() else {
+                    bail!("Text value must be valid UTF-8");
+                };
+                Ok(state.create_userdata(TypedParam(Param::Text(s.to_string()))))
+            },
+        ),
+    );
+    goobie_mysql.raw_set(
+        state,
+        "Blob",
+        state.create_function(|state: &lua::State, s: lua::String| {
+            state.create_userdata(TypedParam(Param::String(s)))
+        }),
+    );
+    goobie_mysql.raw_set(
+        state,
+        "Decimal",
+        state.create_function(
+            |state: &lua::State, v: lua::Value| -> Result<UserDataRef<TypedParam>> {
+                Ok(state.create_userdata(TypedParam(Param::Decimal(parse_decimal(state, v)?))))
+            },
+        ),
+    );
+}
+
+/// Numbers go through their shortest representation, so 12.34 is 12.34 and not 12.339999999999999857891452847979962825775146484375
+fn parse_decimal(state: &lua::State, v: lua::Value) -> Result<Decimal> {
+    let s = match v.type_kind() {
+        lua::ValueKind::Number => v.to::<f64>(state)?.to_string(),
+        lua::ValueKind::String => v.to::<lua::String>(state)?.to_string(),
+        _ => bail!(
+            "Decimal value must be a string or a number, got {}",
+            v.type_name()
+        ),
+    };
+    let s = s.trim();
+    // Decimal's FromStr doesn't take exponents, scientific notation needs its own parser
+    let d = if s.contains(['e', 'E']) {
+        Decimal::from_scientific(s)
+    } else {
+        s.parse()
+    };
+    d.map_err(|e| anyhow!("invalid Decimal value '{s}': {e}"))
+}
+
+pub fn parse_param(state: &lua::State, v: lua::Value) -> Result<Param> {
+    use lua::ValueKind;
+    let param = match v.type_kind() {
+        ValueKind::Bool => Param::Bool(v.to::<bool>(state)?),
+        ValueKind::Number => Param::Number(v.to::<f64>(state)?),
+        ValueKind::String => Param::String(v.to::<lua::String>(state)?),
+        ValueKind::Nil => Param::Null,
+        ValueKind::UserData if is_null(state, &v) => Param::Null,
+        ValueKind::UserData => match v
+            .clone()
+            .to::<AnyUserData>(state)
+            .ok()
+            .and_then(|ud| ud.cast_to::<TypedParam>(state))
+        {
+            Some(typed) => typed.borrow().0.clone(),
+            None => bail!("unsupported parameter type: {}", v.type_name()),
+        },
+        _ => bail!("unsupported parameter type: {}", v.type_name()),
+    };
+    Ok(param)
+}
+
+/// Checks if the value is gmod's `NULL` entity, which is used to represent SQL NULL
+pub fn is_null(state: &lua::State, v: &lua::Value) -> bool {
+    if v.type_kind() != lua::ValueKind::UserData {
+        return false;
+    }
+    let Ok(null) = state.get_global::<lua::Value>("NULL") else {
+        return false;
+    };
+    // userdata keys are compared by identity, so this is a raw equality check
+    let lookup = state.create_table_with_capacity(0, 1);
+    lookup.raw_set(state, null, true);
+    lookup
+        .raw_get::<Option<bool>>(state, v)
+        .ok()
+        .flatten()
+        .unwrap_or(false)
+}
+
+/// A value passed for a placeholder
+#[derive(Clone)]
+enum Arg {
+    Single(Param),
+    /// Expanded into `(?, ?, ?)` for `IN` clauses
+    List(Vec<Param>),
+}
+
+fn parse_arg(state: &lua::State, v: lua::Value) -> Result<Arg> {
+    if v.type_kind() != lua::ValueKind::Table {
+        return Ok(Arg::Single(parse_param(state, v)?));
+    }
+
+    let list = v.to::<Table>(state)?;
+    let mut values = Vec::new();
+    for (i, v) in list.ipairs::<lua::Value>(state) {
+        values.push(parse_param(state, v).map_err(|e| anyhow!("value {i}: {e}"))?);
+    }
+    Ok(Arg::List(values))
+}
+
+impl Arg {
+    fn push_to(self, query: &mut String, out: &mut Vec<Param>) {
+        match self {
+            Arg::Single(param) => {
+                query.push('?');
+                out.push(param);
+            }
+            // `IN ()` is a syntax error, `IN (NULL)` matches nothing
+            Arg::List(values) if values.is_empty() => query.push_str("(NULL)"),
+            Arg::List(values) => {
+                query.push('(');
+                for (i, param) in values.into_iter().enumerate() {
+                    if i > 0 {
+                        query.push_str(", ");
+                    }
+                    query.push('?');
+                    out.push(param);
+                }
+                query.push(')');
+            }
+        }
+    }
+}
+
+/// Resolves `params` against the placeholders in `query`:
+/// - an array binds `?` in order, table values are expanded into lists
+/// - a keyed table binds `:name` placeholders, a name can be used more than once
+///
+/// Returns the query to run if it had to be rewritten, along with the params to bind in order
+pub fn bind_params(
+    state: &lua::State,
+    query: &str,
+    params: Table,
+    session: &SessionInfo,
+) -> Result<(Option<String>, Vec<Param>)> {
+    let len = params.raw_len(state);
+    let positional = len > 0;
+
+    let mut has_lists = false;
+    let mut positional_args = Vec::new();
+    let mut named_args = HashMap::new();
+    if positional {
+        for (i, v) in params.ipairs::<lua::Value>(state) {
+            let arg = parse_arg(state, v).map_err(|e| anyhow!("parameter {i}: {e}"))?;
+            has_lists |= matches!(arg, Arg::List(_));
+            positional_args.push(arg);
+        }
+        // keys past the array part would be dropped without a word
+        for (k, _) in params.pairs::<lua::Value, lua::Value>(state) {
+            let in_array = k.type_kind() == lua::ValueKind::Number
+                && k.to::<f64>(state)
+                    .is_ok_and(|i| i.fract() == 0.0 && i >= 1.0 && i <= len as f64);
+            if !in_array {
+                bail!("params can't mix positional and named parameters");
+            }
+        }
+    } else {
+        for (k, v) in params.pairs::<lua::Value, lua::Value>(state) {
+            if k.type_kind() != lua::ValueKind::String {
+                bail!(
+                    "named parameter keys must be strings, got {}",
+                    k.type_name()
+                );
+            }
+            let name = k.to::<lua::String>(state)?.to_string();
+            let arg = parse_arg(state, v).map_err(|e| anyhow!("parameter '{name}': {e}"))?;
+            named_args.insert(name, (arg, false));
+        }
+    }
+
+    // nothing to rewrite, bind as is
+    if positional && !has_lists {
+        let params = positional_args
+            .into_iter()
+            .map(|arg| match arg {
+                Arg::Single(param) => param,
+                _ => unreachable!("checked by has_lists"),
+            })
+            .collect();
+        return Ok((None, params));
+    }
+    if named_args.is_empty() {
+        return Ok((None, Vec::new()));
+    }
+
+    let pieces = lexer::split(query, !session.no_backslash_escapes);
+    let mut new_query = String::with_capacity(query.len());
     let mut out = Vec::new();
 
-    for (i, v) in params.ipairs::<lua::Value>(state) {
-        use lua::ValueKind;
-        let param = match v.type_kind() {
-            ValueKind::Bool => Param::Bool(v.to::<bool>(state)?),
-            ValueKind::Number => Param::Number(v.to::<f64>(state)?),
-            ValueKind::String => Param::String(v.to::<lua::String>(state)?),
-            _ => bail!("unsupported parameter type {i}: {}", v.type_name()),
-        };
-        out.push(param);
+    if positional {
+        let passed = positional_args.len();
+        let mut args = positional_args.into_iter();
+        for piece in pieces {
+            match piece {
+                Piece::Positional => match args.next() {
+                    Some(arg) => arg.push_to(&mut new_query, &mut out),
+                    None => {
+                        bail!("query has more placeholders than the {passed} parameters passed")
+                    }
+                },
+                piece => piece.push_to(&mut new_query),
+            }
+        }
+        if args.next().is_some() {
+            bail!("{passed} parameters were passed but the query has fewer placeholders");
+        }
+        return Ok((Some(new_query), out));
+    }
+
+    for piece in pieces {
+        match piece {
+            Piece::Positional => bail!("can't mix ? placeholders with named parameters"),
+            Piece::Named(name) => {
+                let Some((arg, used)) = named_args.get_mut(name) else {
+                    bail!("missing named parameter ':{name}'");
+                };
+                *used = true;
+                arg.clone().push_to(&mut new_query, &mut out);
+            }
+            piece => piece.push_to(&mut new_query),
+        }
+    }
+
+    let mut unused = named_args
+        .iter()
+        .filter(|(_, (_, used))| !used)
+        .map(|(name, _)| format!("':{name}'"))
+        .collect::<Vec<_>>();
+    if !unused.is_empty() {
+        unused.sort();
+        bail!("unused named parameters: {}", unused.join(", "));
     }
 
-    Ok(out)
+    Ok((Some(new_query), out))
 }
@@ -12,9 +12,118 @@ use sqlx::{
 #[derive(Debug)]
 pub enum QueryResult {
     Run,
-    Execute(MySqlQueryResult),
+    Execute(ExecuteInfo),
     Rows(Result<Vec<Vec<ColumnValue>>>),
     Row(Result<Option<Vec<ColumnValue>>>),
+    Returning(ExecuteInfo, Result<Vec<Vec<ColumnValue>>>),
+}
+
+impl QueryResult {
+    /// Rows the query gave back
+    pub fn rows(&self) -> &[Vec<ColumnValue>] {
+        match self {
+            QueryResult::Rows(Ok(rows)) | QueryResult::Returning(_, Ok(rows)) => rows,
+            QueryResult::Row(Ok(Some(row))) => std::slice::from_ref(row),
+            _ => &[],
+        }
+    }
+
+    /// Rows given back, or affected for statements that don't give any back
+    pub fn row_count(&self) -> u64 {
+        match self {
+            QueryResult::Execute(info) => info.rows_affected,
+            _ => self.rows().len() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExecuteInfo {
+    /// Matched rows for UPDATEs, sqlx always connects with `CLIENT_FOUND_ROWS` and doesn't give
+    /// the info string that has the changed count
+    pub rows_affected: u64,
+    pub last_insert_id: u64,
+    /// Only set with `extended_info`
+    pub warning_count: Option<u64>,
+    /// Every generated id, only set with `extended_info`
+    pub insert_ids: Option<Vec<u64>>,
+}
+
+impl ExecuteInfo {
+    /// Folds the result of another statement into this one, used when a query is split into multiple statements
+    pub fn merge(&mut self, other: ExecuteInfo) {
+        self.rows_affected += other.rows_affected;
+        // keep the first generated id, same as MySQL does for a multi-row INSERT
+        if self.last_insert_id == 0 {
+            self.last_insert_id = other.last_insert_id;
+        }
+        if let Some(count) = other.warning_count {
+            *self.warning_count.get_or_insert(0) += count;
+        }
+        if let Some(ids) = other.insert_ids {
+            self.insert_ids.get_or_insert_with(Vec::new).extend(ids);
+        }
+    }
+
+    /// MySQL only gives the first generated id, the rest of a single INSERT's ids are `step` apart.
+    /// Only right for plain INSERTs, rows skipped by IGNORE or updated by ON DUPLICATE KEY UPDATE
+    /// still count as affected
+    pub fn set_insert_ids(&mut self, step: u64) {
+        let ids = if self.last_insert_id == 0 {
+            Vec::new()
+        } else {
+            (0..self.rows_affected)
+                .map(|i| self.last_insert_id + i * step)
+                .collect()
+        };
+        self.insert_ids = Some(ids);
+    }
+
+    pub fn to_table(&self, state: &lua::State) -> lua::Table {
+        let table = state.create_table_with_capacity(0, 4);
+        table.raw_set(state, "rows_affected", self.rows_affected);
+        table.raw_set(state, "last_insert_id", self.last_insert_id);
+        if let Some(count) = self.warning_count {
+            table.raw_set(state, "warning_count", count);
+        }
+        if let Some(ids) = &self.insert_ids {
+            table.raw_set(state, "insert_ids", ids.clone());
+        }
+        table
+    }
+}
+
+impl From<MySqlQueryResult> for ExecuteInfo {
+    fn from(res: MySqlQueryResult) -> Self {
+        Self {
+            rows_affected: res.rows_affected(),
+            last_insert_id: res.last_insert_id(),
+            ..Default::default()
+        }
+    }
+}
+
+/// How DECIMAL columns are given to lua
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DecimalMode {
+    /// A number if it fits in a double without losing anything, otherwise a string
+    #[default]
+    Auto,
+    /// Always a string, keeps the exact value and the column's scale, e.g. "12.3400"
+    String,
+    /// Always a number, can lose precision
+    Number,
+}
+
+impl DecimalMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        Ok(match mode {
+            "auto" => Self::Auto,
+            "string" => Self::String,
+            "number" => Self::Number,
+            _ => bail!("decimal must be \"auto\", \"string\" or \"number\", got \"{mode}\""),
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -41,6 +150,21 @@ pub enum Value {
     Decimal(Decimal),
 }
 
+impl Value {
+    /// Roughly how much data the server sent for it
+    pub fn byte_len(&self) -> usize {
+        match self {
+            Value::Nil => 0,
+            Value::Bool(_) | Value::I8(_) | Value::U8(_) => 1,
+            Value::I16(_) | Value::U16(_) => 2,
+            Value::I32(_) | Value::U32(_) | Value::F32(_) => 4,
+            Value::I64(_) | Value::U64(_) | Value::F64(_) => 8,
+            Value::String(s) => s.len(),
+            Value::Decimal(_) => 16,
+        }
+    }
+}
+
 impl ToLua for &Value {
     fn push_to_stack(self, state: &lua::State) {
         match self {
@@ -62,21 +186,26 @@ impl ToLua for &Value {
     }
 }
 
-pub fn convert_rows(rows: &[MySqlRow]) -> Result<Vec<Vec<ColumnValue>>> {
-    rows.iter().map(row_to_values).collect()
+pub fn convert_rows(rows: &[MySqlRow], decimal: DecimalMode) -> Result<Vec<Vec<ColumnValue>>> {
+    rows.iter().map(|row| row_to_values(row, decimal)).collect()
 }
 
-pub fn convert_row(row: &Option<MySqlRow>) -> Result<Option<Vec<ColumnValue>>> {
-    row.as_ref().map(row_to_values).transpose()
+pub fn convert_row(
+    row: &Option<MySqlRow>,
+    decimal: DecimalMode,
+) -> Result<Option<Vec<ColumnValue>>> {
+    row.as_ref()
+        .map(|row| row_to_values(row, decimal))
+        .transpose()
 }
 
-fn row_to_values(row: &MySqlRow) -> Result<Vec<ColumnValue>> {
+fn row_to_values(row: &MySqlRow, decimal: DecimalMode) -> Result<Vec<ColumnValue>> {
     let mut values = Vec::with_capacity(row.columns().len());
 
     for column in row.columns() {
         let name = column.name();
         let col_type = column.type_info().name();
-        let value = extract_column_value(row, name, col_type)?;
+        let value = extract_column_value(row, name, col_type, decimal)?;
 
         values.push(ColumnValue {
             column_name: name.to_string(),
@@ -87,7 +216,12 @@ fn row_to_values(row: &MySqlRow) -> Result<Vec<ColumnValue>> {
     Ok(values)
 }
 
-fn extract_column_value(row: &MySqlRow, column_name: &str, column_type: &str) -> Result<Value> {
+fn extract_column_value(
+    row: &MySqlRow,
+    column_name: &str,
+    column_type: &str,
+    decimal: DecimalMode,
+) -> Result<Value> {
     let raw_value = row.try_get_raw(column_name)?;
     if raw_value.is_null() {
         return Ok(Value::Nil);
@@ -106,7 +240,14 @@ fn extract_column_value(row: &MySqlRow, column_name: &str, column_type: &str) ->
         "BIGINT UNSIGNED" => Value::U64(row.get(column_name)),
         "FLOAT" => Value::F32(row.get(column_name)),
         "DOUBLE" | "REAL" => Value::F64(row.get(column_name)),
-        "DECIMAL" => Value::Decimal(row.get(column_name)),
+        "DECIMAL" => {
+            let d: Decimal = row.get(column_name);
+            match decimal {
+                DecimalMode::Auto => Value::Decimal(d),
+                DecimalMode::String => Value::String(d.to_string().into()),
+                DecimalMode::Number => Value::F64(f64::try_from(d)?),
+            }
+        }
         "TIME" => {
             let time: NaiveTime = row.get(column_name);
             Value::String(time.to_string().into())
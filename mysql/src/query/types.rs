@@ -1,7 +1,25 @@
-use anyhow::Result;
-use gmodx::lua::{self, Function, Table};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::SyncSender,
+    },
+    time::Duration,
+};
 
-use super::{Param, QueryResult, parse_params};
+use anyhow::{Result, bail};
+use gmodx::lua::{self, Function, Table, Thread};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::{Flavor, PendingGuard, SessionInfo},
+    promise::{Promise, PromiseHandle},
+};
+
+use super::{
+    BulkInsert, Param, QueryResult, bind_params,
+    result::DecimalMode,
+    warnings::{Warning, WarningsMode},
+};
 
 #[derive(Debug, Copy, Clone)]
 pub enum QueryType {
@@ -9,49 +27,217 @@ pub enum QueryType {
     Execute,
     FetchOne,
     FetchAll,
+    /// Both the rows and the execute info, for `INSERT/DELETE ... RETURNING` on MariaDB
+    ExecuteReturning,
+}
+
+impl QueryType {
+    pub const ALL: [Self; 5] = [
+        Self::Run,
+        Self::Execute,
+        Self::FetchOne,
+        Self::FetchAll,
+        Self::ExecuteReturning,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::Execute => "execute",
+            Self::FetchOne => "fetch_one",
+            Self::FetchAll => "fetch_all",
+            Self::ExecuteReturning => "execute_returning",
+        }
+    }
+
+    /// From the name of the `Conn` method that runs it
+    pub fn from_method(name: &str) -> Result<Self> {
+        Ok(match name {
+            "Run" => Self::Run,
+            "Execute" => Self::Execute,
+            "ExecuteReturning" => Self::ExecuteReturning,
+            "FetchOne" => Self::FetchOne,
+            "Fetch" => Self::FetchAll,
+            _ => bail!(
+                "query type must be \"Run\", \"Execute\", \"ExecuteReturning\", \"FetchOne\" or \"Fetch\", got \"{name}\""
+            ),
+        })
+    }
+}
+
+/// Which lane of the connection's queue the query waits in, higher lanes are run first
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(usize)]
+pub enum Priority {
+    High = 0,
+    #[default]
+    Normal = 1,
+    Low = 2,
+}
+
+impl Priority {
+    pub fn parse(priority: &str) -> Result<Self> {
+        Ok(match priority {
+            "high" => Self::High,
+            "normal" => Self::Normal,
+            "low" => Self::Low,
+            _ => bail!("priority must be \"high\", \"normal\" or \"low\", got \"{priority}\""),
+        })
+    }
+}
+
+/// Options that can be set per query, or on the connection to be used as defaults
+#[derive(Debug, Clone, Default)]
+pub struct QuerySettings {
+    pub decimal: DecimalMode,
+    /// Adds `warning_count` and `insert_ids` to `Execute` results
+    pub extended_info: bool,
+    pub warnings: WarningsMode,
+    /// Also print errors thrown by the callback when they're given to `on_error`
+    pub rethrow_callback_errors: bool,
+}
+
+impl QuerySettings {
+    pub fn parse(state: &lua::State, opts: &Table, defaults: &QuerySettings) -> Result<Self> {
+        let mut settings = defaults.clone();
+        if let Some(decimal) = opts.get::<Option<lua::String>>(state, "decimal")? {
+            settings.decimal = DecimalMode::parse(&decimal.to_string())?;
+        }
+        if let Some(extended_info) = opts.get::<Option<bool>>(state, "extended_info")? {
+            settings.extended_info = extended_info;
+        }
+        if let Some(warnings) = opts.get::<Option<lua::String>>(state, "warnings")? {
+            settings.warnings = WarningsMode::parse(&warnings.to_string())?;
+        }
+        if let Some(rethrow) = opts.get::<Option<bool>>(state, "rethrow_callback_errors")? {
+            settings.rethrow_callback_errors = rethrow;
+        }
+        Ok(settings)
+    }
+}
+
+static NEXT_QUERY_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Unique across connections, so cancelling with a stale id can't hit another connection's query
+pub fn next_query_id() -> u64 {
+    NEXT_QUERY_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-#[derive(Debug)]
 pub struct Query {
+    /// `query_id` option, the lua side reserves it before queueing so it can be returned right away
+    pub id: u64,
     pub query: String,
     pub qtype: QueryType,
+    pub priority: Priority,
+    /// Set for the statements of a transaction, it doesn't get reordered with the other queries
+    pub in_order: bool,
     pub params: Vec<Param>,
     pub callback: Option<Function>,
     pub on_error: Option<Function>,
     pub raw: bool,
     pub result: Result<QueryResult>,
     pub trace: Option<lua::String>,
+    pub settings: QuerySettings,
+    /// Set for `BulkInsert` and `UpsertQuery`, which build their statements at execution time
+    pub bulk: Option<BulkInsert>,
+    /// Filled when `settings.warnings` isn't `Ignore`
+    pub warnings: Vec<Warning>,
+    /// Set once the query is sent to the connection
+    pub pending: Option<PendingGuard>,
+    /// `timeout` option, how long a `*Sync` call waits for the query
+    pub timeout: Option<Duration>,
+    /// Set by `*Sync` methods, the finished query is sent back instead of calling the callback
+    pub sync: Option<SyncSender<SyncResult>>,
+    /// Set by `Await`, resumed with the results instead of calling the callback
+    pub coroutine: Option<Thread>,
+    /// `promise` option, settled along with calling the callback
+    pub promise: Option<PromiseHandle>,
+    /// Shared with the promise if there's one, skips the query if it's still queued, kills it if
+    /// it's running
+    pub cancel: CancellationToken,
+    /// The server it's sent to, for error codes only one of them has
+    pub flavor: Flavor,
 }
 
+/// A finished query and the server's id for the connection it ran on
+pub type SyncResult = (Query, Option<u64>);
+
 impl Query {
     pub fn new(
         state: &lua::State,
         query: String,
         qtype: QueryType,
         on_error: Option<Function>,
+        defaults: &QuerySettings,
+        session: &SessionInfo,
         opts: Option<Table>,
     ) -> Result<Self> {
         let mut this = Self {
+            id: 0,
             query,
             qtype,
+            priority: Priority::Normal,
+            in_order: false,
             params: Vec::new(),
             callback: None,
             on_error,
             raw: false,
             result: Ok(QueryResult::Run),
             trace: None,
+            settings: defaults.clone(),
+            bulk: None,
+            warnings: Vec::new(),
+            pending: None,
+            timeout: None,
+            sync: None,
+            coroutine: None,
+            promise: None,
+            cancel: CancellationToken::new(),
+            flavor: session.flavor(),
         };
 
         if let Some(opts) = opts {
             this.raw = opts.get::<Option<bool>>(state, "raw")?.unwrap_or(false);
             this.callback = opts.get(state, "callback")?;
             this.trace = opts.get(state, "trace")?;
+            this.settings = QuerySettings::parse(state, &opts, defaults)?;
+            if let Some(priority) = opts.get::<Option<lua::String>>(state, "priority")? {
+                this.priority = Priority::parse(&priority.to_string())?;
+            }
+            this.in_order = opts
+                .get::<Option<bool>>(state, "in_order")?
+                .unwrap_or(false);
+            this.promise = Promise::from_opts(state, Some(&opts))?;
+            if let Some(promise) = &this.promise {
+                this.cancel = promise.cancel_token();
+            }
+            if let Some(id) = opts.get::<Option<u64>>(state, "query_id")? {
+                this.id = id;
+            }
+            // cancelled while it was waiting in the lua queue
+            if opts.get::<Option<bool>>(state, "cancelled")? == Some(true) {
+                this.cancel.cancel();
+            }
+            if let Some(timeout) = opts.get::<Option<f64>>(state, "timeout")? {
+                if !(timeout > 0.0 && timeout.is_finite()) {
+                    bail!("timeout must be a positive number of seconds");
+                }
+                this.timeout = Some(Duration::from_secs_f64(timeout));
+            }
 
             if let Some(params) = opts.get::<Option<Table>>(state, "params")? {
-                this.params = parse_params(state, params)?;
+                let (query, params) = bind_params(state, &this.query, params, session)?;
+                if let Some(query) = query {
+                    this.query = query;
+                }
+                this.params = params;
             }
         }
 
+        if this.id == 0 {
+            this.id = next_query_id();
+        }
+
         Ok(this)
     }
 }
@@ -0,0 +1,105 @@
+use std::fmt;
+
+use anyhow::{Result, bail};
+use gmodx::lua::{self, Table};
+use sqlx::{Executor as _, Row as _, mysql::MySqlConnection};
+
+/// What to do with the warnings a statement leaves behind
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WarningsMode {
+    /// Don't fetch them
+    #[default]
+    Ignore,
+    /// Fetch them and give them to the callback
+    Attach,
+    /// Fetch them and fail the query if there are any
+    Error,
+}
+
+impl WarningsMode {
+    pub fn parse(mode: &str) -> Result<Self> {
+        Ok(match mode {
+            "ignore" => Self::Ignore,
+            "attach" => Self::Attach,
+            "error" => Self::Error,
+            _ => bail!("warnings must be \"ignore\", \"attach\" or \"error\", got \"{mode}\""),
+        })
+    }
+}
+
+/// A row from `SHOW WARNINGS`
+#[derive(Debug, Clone)]
+pub struct Warning {
+    /// "Note", "Warning" or "Error"
+    pub level: String,
+    pub code: u32,
+    pub message: String,
+}
+
+impl Warning {
+    /// Notes are informational, like `CREATE TABLE IF NOT EXISTS` on a table that's already there
+    pub fn is_note(&self) -> bool {
+        self.level.eq_ignore_ascii_case("Note")
+    }
+
+    pub fn to_table(&self, state: &lua::State) -> Table {
+        let table = state.create_table_with_capacity(0, 3);
+        table.raw_set(state, "level", &self.level);
+        table.raw_set(state, "code", self.code);
+        table.raw_set(state, "message", &self.message);
+        table
+    }
+}
+
+pub fn warnings_table(state: &lua::State, warnings: &[Warning]) -> Table {
+    let table = state.create_table_with_capacity(warnings.len() as i32, 0);
+    for (idx, warning) in warnings.iter().enumerate() {
+        table.raw_set(state, idx as i32 + 1, warning.to_table(state));
+    }
+    table
+}
+
+/// Reads the warnings of the last statement, `SHOW WARNINGS` only ever covers one statement, so
+/// this has to run right after it. sqlx doesn't give the OK packet's warning count, so it can't be
+/// skipped for statements that left none, asking for the count would cost the same round trip
+pub async fn fetch(conn: &mut MySqlConnection) -> Result<Vec<Warning>> {
+    let rows = conn.fetch_all("SHOW WARNINGS").await?;
+    rows.iter()
+        .map(|row| {
+            Ok(Warning {
+                level: row.try_get(0)?,
+                code: row
+                    .try_get::<u32, _>(1)
+                    .or_else(|_| row.try_get::<i64, _>(1).map(|c| c as u32))?,
+                message: row.try_get(2)?,
+            })
+        })
+        .collect()
+}
+
+/// Returned instead of the result when `warnings = "error"` and the statement left warnings,
+/// the statement itself has already run by then
+#[derive(Debug)]
+pub struct WarningsError(pub Vec<Warning>);
+
+impl fmt::Display for WarningsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.0.len();
+        write!(f, "query produced {count} warning")?;
+        if count != 1 {
+            write!(f, "s")?;
+        }
+        if let Some(first) = self.0.first() {
+            write!(f, ": {}", first.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for WarningsError {}
+
+impl WarningsError {
+    pub fn write_fields(&self, state: &lua::State, out: &Table) {
+        out.raw_set(state, "warnings", warnings_table(state, &self.0));
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use gmodx::lua::{self, AnyUserData, ObjectLike as _, Value};
+
+use crate::{connection::registry, print_goobie};
+
+const DRAIN_TIMEOUT_CONVAR: &str = "GOOBIE_MYSQL_SHUTDOWN_TIMEOUT";
+const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Acquire)
+}
+
+/// Counts a query whose callback is about to run, only while draining
+pub fn record(ok: bool) {
+    if !is_shutting_down() {
+        return;
+    }
+    if ok {
+        COMPLETED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn on_gmod_open(state: &lua::State) {
+    // created here so it shows up and gets archived, it's read again on shutdown
+    let _ = create_convar(state);
+}
+
+fn create_convar(state: &lua::State) -> Option<AnyUserData> {
+    let globals = state.globals();
+
+    let flags = state.create_table();
+    for (idx, name) in ["FCVAR_ARCHIVE", "FCVAR_PROTECTED"].iter().enumerate() {
+        let cvar_flag = globals.get::<Value>(state, *name).ok()?;
+        flags.set(state, idx + 1, cvar_flag).ok()?;
+    }
+
+    globals
+        .call(
+            state,
+            "CreateConVar",
+            (
+                DRAIN_TIMEOUT_CONVAR,
+                DEFAULT_DRAIN_TIMEOUT,
+                flags,
+                "How long to wait for pending queries on shutdown, in seconds",
+            ),
+        )
+        .ok()
+}
+
+fn drain_timeout(state: &lua::State) -> Duration {
+    // CreateConVar gives back the existing convar
+    let secs = create_convar(state)
+        .and_then(|convar| convar.call_method::<u64>(state, "GetInt", ()).ok())
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+    Duration::from_secs(secs)
+}
+
+/// Stops new queries from being sent, then keeps running callbacks until every connection's
+/// queries are done or the timeout is hit
+pub fn drain(state: &lua::State) {
+    SHUTTING_DOWN.store(true, Ordering::Release);
+
+    let conns = registry::all();
+    let pending = || {
+        conns
+            .iter()
+            .map(|meta| meta.pending_queries.load(Ordering::Acquire))
+            .sum::<usize>()
+    };
+
+    let initial = pending();
+    if initial == 0 {
+        return;
+    }
+
+    let timeout = drain_timeout(state);
+    print_goobie!(
+        "Waiting up to {} seconds for {initial} pending queries...",
+        timeout.as_secs()
+    );
+
+    let deadline = Instant::now() + timeout;
+    while pending() > 0 && Instant::now() < deadline {
+        gmodx::flush_next_tick(state);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    print_goobie!(
+        "Shutdown: {} queries completed, {} failed, {} abandoned",
+        COMPLETED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        pending()
+    );
+}
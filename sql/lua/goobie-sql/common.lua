@@ -164,7 +164,7 @@ local HandleQueryParams; do
         end
     end
 
-    local fquery_params, fquery_new_params
+    local fquery_params, fquery_new_params, fquery_native
     local has_matches = false
     local gsub_f = function(key)
         local raw_value = fquery_params[tonumber(key)]
@@ -174,6 +174,12 @@ local HandleQueryParams; do
 
         has_matches = true
 
+        -- the mysql module binds NULL and typed params itself, and expands lists into (?, ?, ?)
+        if fquery_native then
+            table_insert(fquery_new_params, raw_value)
+            return "?"
+        end
+
         if raw_value == common.NULL then
             return "NULL"
         end
@@ -187,9 +193,10 @@ local HandleQueryParams; do
     local EMPTY_QUERY_PARAMS = {}
     ---@return string
     ---@return table
-    function HandleQueryParams(query, params)
+    function HandleQueryParams(query, params, native)
         fquery_new_params = {}
         fquery_params = params
+        fquery_native = native
         has_matches = false
 
         -- We don't return the query immediately as that could cause hidden bugs. We must ensure that if the developer is using
@@ -207,6 +214,16 @@ local HandleQueryParams; do
             return query, params
         end
 
+        -- only the array part is used for {1} placeholders, named keys would be dropped silently
+        if native then
+            local len = #fquery_params
+            for k in pairs(fquery_params) do
+                if type(k) ~= "number" or k < 1 or k > len or k % 1 ~= 0 then
+                    errorlevelf(4, "params can't mix positional and named parameters")
+                end
+            end
+        end
+
         return new_query, fquery_new_params
     end
 end
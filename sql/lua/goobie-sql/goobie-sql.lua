@@ -13,6 +13,17 @@ local goobie_sql = {
 local goobie_sqlite
 local goobie_mysql
 
+-- returns the mysql binary module table, for things like goobie_mysql.Text/Escape that aren't tied to a connection
+function goobie_sql.GetMySQL()
+    if goobie_mysql == nil then
+        goobie_mysql = include("goobie-sql/mysql/main.lua")
+        if not goobie_mysql then
+            return error("failed to load mysql binary module")
+        end
+    end
+    return goobie_mysql
+end
+
 function goobie_sql.NewConn(opts, on_connected)
     if type(opts) ~= "table" then
         return error("opts must be a table")
@@ -27,13 +38,7 @@ function goobie_sql.NewConn(opts, on_connected)
 
     local conn
     if driver == "mysql" then
-        if goobie_mysql == nil then
-            goobie_mysql = include("goobie-sql/mysql/main.lua")
-            if not goobie_mysql then
-                return error("failed to load mysql binary module")
-            end
-        end
-        conn = goobie_mysql.NewConn(opts)
+        conn = goobie_sql.GetMySQL().NewConn(opts)
     else
         if goobie_sqlite == nil then
             goobie_sqlite = include("goobie-sql/sqlite/main.lua")
@@ -22,10 +22,6 @@ goobie_mysql.lua_loaded = true
 local type = type
 local tostring = tostring
 local CheckQuery = common.CheckQuery
-local table_HasValue = table.HasValue
-local table_concat = table.concat
-local table_insert = table.insert
-local string_format = string.format
 local string_gsub = string.gsub
 
 local CROSS_SYNTAXES = common.CROSS_SYNTAXES.mysql
@@ -37,37 +33,63 @@ local Conn = goobie_mysql.CONN_META
 local QUERIES = {
     Run = Conn.Run,
     Execute = Conn.Execute,
+    ExecuteReturning = Conn.ExecuteReturning,
     FetchOne = Conn.FetchOne,
     Fetch = Conn.Fetch,
 }
 
+-- native versions block on that one query only, they don't run anyone else's callbacks while waiting
+local SYNC_QUERIES = {
+    Run = Conn.RunSync,
+    Execute = Conn.ExecuteSync,
+    ExecuteReturning = Conn.ExecuteReturningSync,
+    FetchOne = Conn.FetchOneSync,
+    Fetch = Conn.FetchSync,
+}
+
 for k, v in pairs(common.COMMON_META) do
     Conn[k] = v
 end
 
 local function ConnSyncOP(conn, op)
     local done
-    local err, res
-    op(function(e, r)
+    local err, res, warnings
+    op(function(e, r, w)
         done = true
-        err, res = e, r
+        err, res, warnings = e, r, w
     end)
     while not done do
         conn:Poll()
     end
-    return err, res
+    return err, res, warnings
+end
+
+-- a transaction holds the connection, so the query has to wait in the queue behind it, polling is
+-- the only way to get there without deadlocking
+local function ConnCanRunSync(conn)
+    return not common.GetPrivate(conn, "locked")
 end
 
-local function ConnQueueTask(conn, func, p1, p2, p3)
+-- made before queueing instead of by the module, so they can be returned even if the query has to wait
+local function PrepareHandles(opts)
+    local query_id = goobie_mysql.NewQueryID()
+    opts.query_id = query_id
+    if opts.promise == true then
+        opts.promise = goobie_mysql.NewPromise()
+    end
+    return query_id, opts.promise or nil
+end
+
+local function ConnQueueTask(conn, func, p1, p2, p3, p4)
     if common.GetPrivate(conn, "locked") then
         local txn = common.GetPrivate(conn, "txn")
         if txn and txn.open and coroutine.running() == txn.co then
             return error("you can't run queries on a `connection` inside an open transaction's coroutine", 2)
         end
         local queue = common.GetPrivate(conn, "queue")
-        queue[#queue + 1] = { func, p1, p2, p3 }
+        queue[#queue + 1] = { func, p1, p2, p3, p4 }
     else
-        func(conn, p1, p2, p3)
+        func(conn, p1, p2, p3, p4)
     end
 end
 
@@ -85,7 +107,30 @@ local function ConnProcessQueue(conn)
         local task = queue[i]
         -- we call QueueTask again because a task can be a Transaction Begin
         local func = task[1]
-        ConnQueueTask(conn, func, task[2], task[3], task[4])
+        ConnQueueTask(conn, func, task[2], task[3], task[4], task[5])
+    end
+end
+
+do
+    local RealCancel = Conn.Cancel
+
+    function Conn:Cancel(query_id)
+        if type(query_id) ~= "number" then
+            return error("query id must be a number", 2)
+        end
+        -- still waiting behind a transaction, the module hasn't seen it yet
+        local queue = common.GetPrivate(self, "queue")
+        for i = 1, #queue do
+            local task = queue[i]
+            for j = 2, 5 do
+                local opts = task[j]
+                if type(opts) == "table" and opts.query_id == query_id then
+                    opts.cancelled = true
+                    return true
+                end
+            end
+        end
+        return RealCancel(self, query_id)
     end
 end
 
@@ -111,6 +156,13 @@ function Conn:DisconnectSync()
     return err
 end
 
+function Conn:CloseSync()
+    local err = ConnSyncOP(self, function(cb)
+        self:Close(cb)
+    end)
+    return err
+end
+
 function Conn:PingSync()
     local done, err, res
     self:Ping(function(e, r)
@@ -127,8 +179,10 @@ local function prepare_query(query, opts)
     opts = CheckQuery(query, opts)
     query = string_gsub(query, "{([%w_]+)}", CROSS_SYNTAXES)
     local params = opts.params
-    if not opts.raw then -- raw queries can't be escaped in sqlx, hopefully they expose an escape function
-        query, params = common.HandleQueryParams(query, params)
+    -- raw queries only get placeholders replaced if params are passed, they get escaped into the query by the module
+    -- CheckQuery fills in an empty params table, so it has to be checked for values
+    if not opts.raw or next(params) ~= nil then
+        query, params = common.HandleQueryParams(query, params, true)
     end
     opts.params = params
     if opts.trace == nil then
@@ -139,37 +193,67 @@ end
 
 local function create_query_method(query_type)
     local query_func = QUERIES[query_type]
+    local sync_query_func = SYNC_QUERIES[query_type]
+
+    local function run_sync(self, query, opts)
+        if ConnCanRunSync(self) then
+            return sync_query_func(self, query, opts)
+        end
+        return ConnSyncOP(self, function(cb)
+            opts.callback = cb
+            ConnQueueTask(self, query_func, query, opts)
+        end)
+    end
 
     Conn[query_type] = function(self, query, opts)
         query, opts = prepare_query(query, opts)
         if opts.sync then
             local callback = opts.callback
-            local err, res = ConnSyncOP(self, function(cb)
-                opts.callback = cb
-                ConnQueueTask(self, query_func, query, opts)
-            end)
+            opts.callback = nil
+            local err, res, warnings = run_sync(self, query, opts)
             if callback then
-                return callback(err, res)
+                return callback(err, res, warnings)
             end
         else
+            local query_id, promise = PrepareHandles(opts)
             ConnQueueTask(self, query_func, query, opts)
+            if promise then return promise, query_id end
+            return query_id
         end
     end
 
     Conn[query_type .. "Sync"] = function(self, query, opts)
         query, opts = prepare_query(query, opts)
-        return ConnSyncOP(self, function(cb)
-            opts.callback = cb
-            ConnQueueTask(self, query_func, query, opts)
-        end)
+        return run_sync(self, query, opts)
     end
 end
 
 create_query_method("Run")
 create_query_method("Execute")
+create_query_method("ExecuteReturning")
 create_query_method("Fetch")
 create_query_method("FetchOne")
 
+do
+    local RealAwait = Conn.Await
+    local coroutine_running = coroutine.running
+    local coroutine_yield = coroutine.yield
+
+    function Conn:Await(query_type, query, opts)
+        if not QUERIES[query_type] then
+            return error("invalid query type: " .. tostring(query_type), 2)
+        end
+        local co, is_main = coroutine_running()
+        if co == nil or is_main then
+            return error("Await can only be called inside a coroutine", 2)
+        end
+        query, opts = prepare_query(query, opts)
+        opts.callback = nil
+        ConnQueueTask(self, RealAwait, query_type, query, opts, co)
+        return coroutine_yield()
+    end
+end
+
 -- someone could ask, why the hell is this function synchronous? because for obvious reasons,
 -- you use this function when setting up your server, so it's not a big deal if it's synchronous
 function Conn:TableExists(name)
@@ -184,111 +268,163 @@ function Conn:TableExists(name)
 end
 
 do
-    local query_count = 0
-    local query_parts = {}
+    local RealUpsertQuery = Conn.UpsertQuery
+    local RealUpsertQuerySync = Conn.UpsertQuerySync
 
-    local function insert_to_query(str)
-        query_count = query_count + 1
-        query_parts[query_count] = str
-    end
-
-    local function ConnUpsertQuery(conn, tbl_name, opts, sync)
+    local function prepare_upsert_query(tbl_name, opts)
         if type(tbl_name) ~= "string" then
-            return error("table name must be a string")
+            return error("table name must be a string", 3)
         end
-
-        query_count = 0
-
-        -- mysql doesn't use primary keys, so we don't need to check for them to keep consistency with sqlite
-        if not opts.primary_keys then
-            return error("upsert query must have primary_keys")
+        if type(opts) ~= "table" then
+            return error("opts must be a table", 3)
         end
-
-        local inserts = opts.inserts
-        local updates = opts.updates
-        local no_escape_columns = opts.no_escape_columns
-
-        local params = { nil, nil, nil, nil, nil, nil }
-        local values = { nil, nil, nil, nil, nil, nil }
-
-        -- INSERT INTO `tbl_name`(`column1`, ...) VALUES(?, ?, ...) ON DUPLICATE KEY UPDATE `column1`=VALUES(`column1`), ...
-        insert_to_query("INSERT INTO`")
-        insert_to_query(tbl_name)
-        insert_to_query("`(")
-
-        for column, value in pairs(inserts) do
-            insert_to_query("`" .. column .. "`")
-            insert_to_query(",")
-            if no_escape_columns and table_HasValue(no_escape_columns, column) then
-                table_insert(values, common.HandleNoEscape(value))
-            else
-                if value == common.NULL then
-                    table_insert(values, "NULL")
-                else
-                    table_insert(values, "?")
-                    table_insert(params, value)
-                end
-            end
-        end
-        query_count = query_count - 1 -- remove last comma
-
-        insert_to_query(")VALUES(")
-        insert_to_query(table_concat(values, ","))
-        insert_to_query(")ON DUPLICATE KEY UPDATE")
-
-        -- basically, if there are no updates, we just update the first column with itself
-        if updates == nil or #updates == 0 then
-            local next_key = next(inserts)
-            updates = { next_key }
+        if opts.trace == nil then
+            opts.trace = debug.traceback("", 3)
         end
+        return opts
+    end
 
-        for i = 1, #updates do
-            local column = updates[i]
-            insert_to_query(string_format("`%s`=VALUES(`%s`)", column, column))
-            insert_to_query(",")
+    function Conn:UpsertQuery(tbl_name, opts)
+        opts = prepare_upsert_query(tbl_name, opts)
+        if opts.return_query then
+            return RealUpsertQuery(self, tbl_name, opts)
         end
-        query_count = query_count - 1 -- remove last comma
-
-        local query = table_concat(query_parts, nil, 1, query_count)
+        local query_id, promise = PrepareHandles(opts)
+        ConnQueueTask(self, RealUpsertQuery, tbl_name, opts)
+        if promise then return promise, query_id end
+        return query_id
+    end
 
+    function Conn:UpsertQuerySync(tbl_name, opts)
+        opts = prepare_upsert_query(tbl_name, opts)
         if opts.return_query then
-            return query, params
+            return RealUpsertQuery(self, tbl_name, opts)
+        end
+        if ConnCanRunSync(self) then
+            return RealUpsertQuerySync(self, tbl_name, opts)
         end
+        return ConnSyncOP(self, function(cb)
+            opts.callback = cb
+            ConnQueueTask(self, RealUpsertQuery, tbl_name, opts)
+        end)
+    end
+end
 
-        opts.params = params
+do
+    local RealBulkInsert = Conn.BulkInsert
+    local RealBulkInsertSync = Conn.BulkInsertSync
 
-        if sync then
-            local err, res = conn:ExecuteSync(query, opts)
-            return err, res
-        else
-            local err, res = conn:Execute(query, opts)
-            return err, res
+    local function prepare_bulk_insert(tbl_name, columns, rows, opts)
+        if type(tbl_name) ~= "string" then
+            return error("table name must be a string", 3)
+        end
+        if type(columns) ~= "table" then
+            return error("columns must be a table", 3)
+        end
+        if type(rows) ~= "table" then
+            return error("rows must be a table", 3)
+        end
+        if opts == nil then
+            opts = {}
+        elseif type(opts) ~= "table" then
+            return error("opts must be a table", 3)
         end
+        if opts.trace == nil then
+            opts.trace = debug.traceback("", 3)
+        end
+        return opts
     end
 
-    function Conn:UpsertQuery(tbl_name, opts)
-        return ConnUpsertQuery(self, tbl_name, opts, false)
+    function Conn:BulkInsert(tbl_name, columns, rows, opts)
+        opts = prepare_bulk_insert(tbl_name, columns, rows, opts)
+        local query_id, promise = PrepareHandles(opts)
+        ConnQueueTask(self, RealBulkInsert, tbl_name, columns, rows, opts)
+        if promise then return promise, query_id end
+        return query_id
     end
 
-    function Conn:UpsertQuerySync(tbl_name, opts)
-        return ConnUpsertQuery(self, tbl_name, opts, true)
+    function Conn:BulkInsertSync(tbl_name, columns, rows, opts)
+        opts = prepare_bulk_insert(tbl_name, columns, rows, opts)
+        if ConnCanRunSync(self) then
+            return RealBulkInsertSync(self, tbl_name, columns, rows, opts)
+        end
+        return ConnSyncOP(self, function(cb)
+            opts.callback = cb
+            ConnQueueTask(self, RealBulkInsert, tbl_name, columns, rows, opts)
+        end)
     end
 end
 
+-- queued as a whole, so it locks the connection as soon as START TRANSACTION is sent and the tasks
+-- queued after it wait for the transaction
 function Conn:Begin(callback)
-    return ConnBegin(self, callback, false)
+    if type(callback) ~= "function" then
+        return error("callback must be a function", 2)
+    end
+    -- taken here, ConnBegin can be called later from the queue
+    local traceback = debug.traceback("", 2)
+    return ConnQueueTask(self, ConnBegin, callback, false, traceback)
 end
 
 function Conn:BeginSync(callback)
-    return ConnBegin(self, callback, true)
+    if type(callback) ~= "function" then
+        return error("callback must be a function", 2)
+    end
+    local traceback = debug.traceback("", 2)
+    local started = false
+    ConnQueueTask(self, function(conn)
+        started = true
+        ConnBegin(conn, callback, true, traceback)
+    end)
+    while not started do
+        self:Poll()
+    end
 end
 
+-- every connection made by any addon, weak so being listed here doesn't keep one alive
+local CONNECTIONS = setmetatable({}, { __mode = "k" })
+local NAMED_CONNECTIONS = setmetatable({}, { __mode = "v" })
+
 local RealNewConn = goobie_mysql.NewConn
 function goobie_mysql.NewConn(opts)
     local conn = RealNewConn(opts)
     common.SetPrivate(conn, "queue", {})
     common.SetPrivate(conn, "ConnProcessQueue", ConnProcessQueue)
+    CONNECTIONS[conn] = true
+    local name = conn:Name()
+    if name then
+        NAMED_CONNECTIONS[name] = conn
+    end
     return conn
 end
 
+function goobie_mysql.GetConn(name)
+    local conn = NAMED_CONNECTIONS[name]
+    if conn and not conn:IsClosed() then
+        return conn
+    end
+end
+
+-- the conn is behind a weak table, so holding on to the list doesn't keep it alive either
+local CONNECTION_ENTRY_META = {
+    __index = {
+        Conn = function(self) return self.ref.conn end,
+    },
+}
+
+function goobie_mysql.Connections()
+    local list = {}
+    for conn in pairs(CONNECTIONS) do
+        if not conn:IsClosed() then
+            list[#list + 1] = setmetatable({
+                ref = setmetatable({ conn = conn }, { __mode = "v" }),
+                name = conn:Name(),
+                info = tostring(conn),
+                stats = conn:Stats(),
+            }, CONNECTION_ENTRY_META)
+        end
+    end
+    return list
+end
+
 return goobie_mysql
@@ -8,7 +8,8 @@ local Txn = {}
 local Txn_MT = { __index = Txn }
 
 -- mysql does not support begin/commit/rollback using prepared statements
-local IS_RAW = { raw = true }
+-- in_order keeps queries that were queued with another priority from running inside the transaction
+local IS_RAW = { raw = true, in_order = true }
 
 local function NewTransaction(conn, co, traceback)
     return setmetatable({
@@ -59,12 +60,16 @@ function TxnQuery(txn, query_type, query, opts)
         opts.trace = debug.traceback("", 2)
     end
 
+    -- a high priority query could go to the dedicated connection, outside of the transaction
+    opts.priority = nil
+    opts.in_order = true
+
     local conn = txn.conn
     -- we need to set locked to false to make sure queries are not queued
     -- it's not an issue if it errors or not because TxnResume will handle it anyway
 
-    opts.callback = function(err, res)
-        TxnResume(txn, err, res)
+    opts.callback = function(err, res, warnings)
+        TxnResume(txn, err, res, warnings)
     end
 
     common.SetPrivate(conn, "locked", false)
@@ -131,6 +136,10 @@ function Txn:Execute(query, opts)
     return TxnQuery(self, "Execute", query, opts)
 end
 
+function Txn:ExecuteReturning(query, opts)
+    return TxnQuery(self, "ExecuteReturning", query, opts)
+end
+
 function Txn:Fetch(query, opts)
     return TxnQuery(self, "Fetch", query, opts)
 end
@@ -154,6 +163,39 @@ function Txn:UpsertQuery(tbl_name, opts)
     return TxnQuery(self, "UpsertQuery", tbl_name, opts)
 end
 
+local function TxnBulkQuery(txn, method, tbl_name, columns, data, opts)
+    if not txn.open then
+        return error("transaction is closed", 3)
+    end
+
+    if opts == nil then
+        opts = {}
+    end
+
+    if opts.trace == nil then
+        opts.trace = debug.traceback("", 3)
+    end
+
+    opts.priority = nil
+    opts.in_order = true
+
+    local conn = txn.conn
+
+    opts.callback = function(err, res, warnings)
+        TxnResume(txn, err, res, warnings)
+    end
+
+    common.SetPrivate(conn, "locked", false)
+    conn[method](conn, tbl_name, columns, data, opts)
+    common.SetPrivate(conn, "locked", true)
+
+    return coroutine.yield()
+end
+
+function Txn:BulkInsert(tbl_name, columns, rows, opts)
+    return TxnBulkQuery(self, "BulkInsert", tbl_name, columns, rows, opts)
+end
+
 function Txn:Commit()
     return TxnFinalize(self, "commit")
 end
@@ -162,15 +204,12 @@ function Txn:Rollback()
     return TxnFinalize(self, "rollback")
 end
 
-local function ConnBegin(conn, callback, sync)
-    if type(callback) ~= "function" then
-        return error("callback must be a function")
-    end
-
-    local traceback = debug.traceback("", 2)
+-- traceback is where Begin was called, for the "transaction was left open!" error
+local function ConnBegin(conn, callback, sync, traceback)
     local callback_done = false
     conn:Run("START TRANSACTION;", {
         raw = true,
+        in_order = true,
         callback = function(err)
             callback_done = true
 
@@ -183,6 +222,10 @@ local function ConnBegin(conn, callback, sync)
             if err then
                 txn.open = false
                 TxnResume(txn, err)
+                -- it never started, so nothing else would unlock it
+                common.SetPrivate(conn, "txn", nil)
+                common.SetPrivate(conn, "locked", false)
+                common.GetPrivate(conn, "ConnProcessQueue")(conn)
             else
                 TxnResume(txn, nil, txn)
             end
@@ -195,6 +238,8 @@ local function ConnBegin(conn, callback, sync)
             end
         end,
     })
+    -- queries sent before the callback runs would end up inside the transaction
+    common.SetPrivate(conn, "locked", true)
 
     if sync then
         while not callback_done do